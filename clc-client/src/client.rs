@@ -1,31 +1,71 @@
-use std::io::{stdin, stdout, Write};
 use std::{thread, time};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Formatter};
-use std::process::exit;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::sync::mpsc::Sender;
 use std::thread::JoinHandle;
-use getch::Getch;
-use terminal_size::{Height, terminal_size, Width};
+use tokio::sync::mpsc::UnboundedSender;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use tungstenite::Message;
-use clc_lib::protocol::{ChatId, ChatTitle, ServerUrl, UserId, UserName, Version};
+use clc_lib::protocol::{ChatId, ChatTitle, ClientWsMessage, Feature, MessageId, Negotiated, RequestId, ServerUrl, SessionToken, UserId, UserName, Version};
+use clc_lib::validator::Rules;
 use crate::input_handler::handle_input;
+use crate::tui;
 use crate::web_client::{Location};
 
+// lines kept in the message pane
+const SCROLLBACK: usize = 2000;
+
 pub(crate) type ThreadClient = Arc<Mutex<Client>>;
 
 pub(crate) struct Client {
     pub(crate) input: String,
+    pub(crate) prompt: String,
+    // echo '*' instead of the input, used for passwords
+    pub(crate) mask_input: bool,
+    // message pane, oldest first
+    pub(crate) lines: VecDeque<String>,
+    // rows the message pane is scrolled up from the bottom
+    pub(crate) scroll: usize,
     pub(crate) loc: Location,
     pub(crate) user_id: Option<UserId>,
+    pub(crate) session: Option<SessionToken>,
     pub(crate) name: Option<UserName>,
+    // the active chat, messages and chat commands go there
     pub(crate) chat_id: Option<ChatId>,
-    pub(crate) chat_title: Option<ChatTitle>,
-    pub(crate) is_admin: bool,
+    pub(crate) chats: HashMap<ChatId, JoinedChat>,
     pub(crate) server: Option<ServerUrl>,
     pub(crate) server_version: Option<Version>,
+    pub(crate) protocol: Option<Negotiated>,
+    // the server's limits, input is checked against them before sending
+    pub(crate) rules: Option<Rules>,
     pub(crate) socket: Option<JoinHandle<()>>,
-    pub(crate) sender: Option<Sender<Message>>
+    pub(crate) sender: Option<UnboundedSender<Message>>,
+    pub(crate) next_request: RequestId,
+    // requests the server did not reply to yet
+    pub(crate) pending: HashMap<RequestId, ClientWsMessage>
+}
+
+pub(crate) struct JoinedChat {
+    pub(crate) title: ChatTitle,
+    pub(crate) is_admin: bool,
+    // oldest history message received, /h loads messages before this one
+    pub(crate) oldest_message: Option<MessageId>,
+    // messages received while another chat was active
+    pub(crate) unread: usize,
+    // the next history page shows the unread messages, it doesn't move oldest_message
+    pub(crate) catching_up: bool,
+}
+
+impl JoinedChat {
+    pub(crate) fn new(title: ChatTitle, is_admin: bool) -> Self {
+        Self {
+            title,
+            is_admin,
+            oldest_message: None,
+            unread: 0,
+            catching_up: false
+        }
+    }
 }
 
 pub(crate) trait ClientSeal {
@@ -42,74 +82,130 @@ impl Client {
     pub(crate) fn new() -> Self {
         Self {
             input: String::new(),
+            prompt: String::from("> "),
+            mask_input: false,
+            lines: VecDeque::new(),
+            scroll: 0,
             loc: Location::Home,
             user_id: None,
+            session: None,
             name: None,
             chat_id: None,
-            chat_title: None,
-            is_admin: false,
+            chats: HashMap::new(),
             server: None,
             server_version: None,
+            protocol: None,
+            rules: None,
             socket: None,
-            sender: None
+            sender: None,
+            next_request: 0,
+            pending: HashMap::new()
         }
     }
 
     pub(crate) fn run_cli(self){
         let client = Arc::new(Mutex::new(self));
+        tui::init();
         loop {
             Self::prompt_input(&client);
             handle_input(&client)
         }
     }
 
-    fn term_size() -> (u16, u16){
-        if let Some((Width(w), Height(h))) = terminal_size() {
-            (w, h)
-        } else {
-            panic!("\rUnable to get terminal size. Please use different terminal");
+    pub(crate) fn writeln(&mut self, line: &str) {
+        self.lines.push_back(line.to_string());
+        while self.lines.len() > SCROLLBACK {
+            self.lines.pop_front();
+        }
+        // keep the view in place while scrolled up
+        if self.scroll > 0 {
+            self.scroll += line.split('\n').count();
         }
+        self.redraw();
     }
 
-    pub(crate) fn writeln(&self, line: &str) {
-        let (w, _) = Self::term_size();
-        println!("\r{:1$}", line, w as usize);
-        self.refresh_input();
+    pub(crate) fn redraw(&mut self) {
+        tui::draw(self);
     }
 
-    fn refresh_input(&self){
-        let (w, _) = Self::term_size();
-        print!("\r{}", " ".repeat(w as usize));
-        let s = format!("> {}", self.input);
-        //print!("\r{:1$}", s, w as usize);
-        print!("\r> {}", self.input);
-        let _ = stdout().flush();
+    // forget everything about the server, the socket has to be closed already
+    pub(crate) fn reset_connection(&mut self) {
+        self.server = None;
+        self.user_id = None;
+        self.session = None;
+        self.name = None;
+        self.chat_id = None;
+        self.chats.clear();
+        self.server_version = None;
+        self.protocol = None;
+        self.rules = None;
+        self.socket = None;
+        self.sender = None;
+        self.pending.clear();
+        self.loc = Location::Home;
+    }
+
+    // everything is allowed until connected, the server decides then
+    pub(crate) fn supports(&self, feature: Feature) -> bool {
+        self.protocol.as_ref().is_none_or(|Negotiated(_, features)| features.contains(&feature))
+    }
+
+    pub(crate) fn active_chat(&self) -> Option<&JoinedChat> {
+        self.chat_id.as_ref().and_then(|chat_id| self.chats.get(chat_id))
+    }
+
+    // lines concerning another chat than the active one are prefixed with its title
+    pub(crate) fn writeln_chat(&mut self, chat_id: &ChatId, line: &str) {
+        match self.chats.get(chat_id) {
+            Some(chat) if self.chat_id.as_ref() != Some(chat_id) => {
+                let line = format!("[{}] {}", chat.title, line);
+                self.writeln(&line)
+            }
+            _ => self.writeln(line)
+        }
+    }
+
+    pub(crate) fn prompt_secret(client: &ThreadClient, prompt: &str) -> String {
+        {
+            let mut c = client.seal();
+            c.prompt = prompt.to_string();
+            c.mask_input = true;
+        }
+        Self::prompt_input(client);
+        let mut c = client.seal();
+        c.prompt = String::from("> ");
+        c.mask_input = false;
+        let input = std::mem::take(&mut c.input);
+        c.redraw();
+        input
     }
 
     fn prompt_input(client: &ThreadClient) {
-        let getch = Getch::new();
         client.seal().input = String::new();
-        client.seal().refresh_input();
-        let _ = stdout().flush();
+        client.seal().redraw();
         loop {
-            if let Ok(c) = getch.getch() {
-                let ch = c as char;
-                if c == 3 { // ^c
-                    exit(0);
-                }
-                else if c == 8 { // delete
-                    client.seal().input.pop();
-                    client.seal().refresh_input();
-                }
-                else if c == 13 { // \n
-                    break
-                }
-                // is printable?
-                else if ch.is_ascii() {
-                    client.seal().input.push(ch);
-                    client.seal().refresh_input();
-                }
+            let event = match event::read() {
+                Ok(event) => event,
+                Err(_) => continue
+            };
+            let mut c = client.seal();
+            match event {
+                Event::Key(key) if key.kind != KeyEventKind::Release => match key.code {
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => tui::exit(0),
+                    KeyCode::Enter => break,
+                    KeyCode::Backspace => {
+                        c.input.pop();
+                    }
+                    KeyCode::PageUp => c.scroll += tui::pane_height() / 2,
+                    KeyCode::PageDown => c.scroll = c.scroll.saturating_sub(tui::pane_height() / 2),
+                    KeyCode::Char(ch) if !ch.is_control() => c.input.push(ch),
+                    _ => {}
+                },
+                // redrawn below
+                Event::Resize(_, _) => {}
+                _ => continue
             }
+            c.redraw();
         }
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,7 @@
-use std::process::exit;
-use clc_lib::protocol::{ChatId, ChatTitle, ClientWsMessage, FilePath, InviteId, ServerUrl, UserName};
+use clc_lib::protocol::{ChatTitle, ClientWsMessage, Feature, FileId, FilePath, InviteId, ModAction, Role, Seconds, ServerUrl, UserName, Visibility};
 use crate::Client;
 use crate::client::{ClientSeal, ThreadClient};
+use crate::tui;
 use crate::web_client::Location;
 
 #[derive(Clone)]
@@ -9,14 +9,36 @@ pub(crate) enum Command {
     Help,
     Info,
     Connect(ServerUrl, UserName),
+    Register(ServerUrl, UserName),
     CreateChat(ChatTitle),
-    Join(ChatId, InviteId),
+    // a chat title or id, public chats need no invite
+    Join(ChatTitle, Option<InviteId>),
+    Directory,
     ListMembers,
-    CreateInvite,
+    // uses, 0 for unlimited, and seconds valid, given in minutes
+    CreateInvite(Option<u32>, Option<Seconds>),
+    // lists the open invites if no id is given
+    Invites(Option<InviteId>),
     Kick(UserName),
+    // durations in seconds, given in minutes
+    Ban(UserName, Option<Seconds>),
+    Mute(UserName, Option<Seconds>),
+    Pardon(UserName),
     Quit,
     Upload(FilePath),
+    Download(FileId),
+    ListFiles,
     Admin(UserName),
+    Demote(UserName),
+    TransferOwner(UserName),
+    History,
+    DirectMessage(UserName, String),
+    // lists the joined chats if no title is given
+    Switch(Option<ChatTitle>),
+    SetNick(UserName),
+    // None removes the topic
+    Topic(Option<String>),
+    SetVisibility(Visibility),
     SendMessage(String)
 }
 
@@ -30,21 +52,49 @@ impl Command {
                 Command::Help => '?',
                 Command::Info => 'i',
                 Command::Connect(_, _) => 'c',
+                Command::Register(_, _) => 'r',
                 Command::CreateChat(_) => 'p',
-                Command::Join(_, _) => 'j',
+                Command::Join(_, _) | Command::Directory => 'j',
                 Command::ListMembers => 'l',
-                Command::CreateInvite => 'n',
+                Command::CreateInvite(_, _) => 'n',
+                Command::Invites(_) => 'x',
                 Command::Kick(_) => 'k',
+                Command::Ban(_, _) => 'b',
+                Command::Mute(_, _) => 'm',
+                Command::Pardon(_) => 'u',
                 Command::Quit => 'q',
                 Command::Upload(_) => 'f',
+                Command::Download(_) => 'g',
+                Command::ListFiles => 'a',
                 Command::Admin(_) => 'y',
+                Command::Demote(_) => 'd',
+                Command::TransferOwner(_) => 'o',
+                Command::History => 'h',
+                Command::DirectMessage(_, _) => 'w',
+                Command::Switch(_) => 's',
+                Command::SetNick(_) => 'e',
+                Command::Topic(_) => 't',
+                Command::SetVisibility(_) => 'v',
                 Command::SendMessage(_) => unreachable!()
             })
         }
     }
+
+    // the part of the protocol the server has to support for this command
+    fn feature(&self) -> Option<Feature> {
+        match self {
+            Command::Upload(_) | Command::Download(_) | Command::ListFiles => Some(Feature::Files),
+            Command::History => Some(Feature::History),
+            Command::DirectMessage(_, _) => Some(Feature::DirectMessages),
+            _ => None
+        }
+    }
 }
 
 const COMMAND_HELP: &'static str = include_str!("../command-help.md");
+const HISTORY_PAGE: usize = 20;
+// longer input is refused instead of flooding the chat
+const MAX_MESSAGE_PARTS: usize = 5;
 
 pub(crate) fn handle_input(client: &ThreadClient) {
     let mut input = client.seal().input.to_owned();
@@ -61,6 +111,9 @@ pub(crate) fn handle_input(client: &ThreadClient) {
         Ok(Command::Help) => {
             client.seal().writeln(COMMAND_HELP);
         }
+        Ok(cmd) if cmd.feature().is_some_and(|feature| !client.seal().supports(feature)) => {
+            client.seal().writeln(&format!("'{}' is not supported by this server", cmd.cmd_ident()));
+        }
         Ok(cmd) => match {
             let loc = client.seal().loc.clone();
             loc
@@ -71,14 +124,21 @@ pub(crate) fn handle_input(client: &ThreadClient) {
                         let mut info = String::new();
                         info.push_str(&format!("client-version: {}\n", env!("CARGO_PKG_VERSION")));
                         info.push_str(&format!("location: {}\n", client.seal().loc));
-                        info.push_str("\nConnect to a server with '/c <url> <name>'");
+                        info.push_str("\nConnect to a server with '/c <url> <name>'\nor create an account with '/r <url> <name>'");
                         client.seal().writeln(info.trim_end());
                     }
                     Command::Quit => {
-                        exit(0)
+                        tui::exit(0)
                     }
                     Command::Connect(url, name) => {
-                        Client::connect_server(client, url, name);
+                        let password = Client::prompt_secret(client, "password: ");
+                        Client::connect_server(client, url, name, password);
+                    }
+                    Command::Register(url, name) => {
+                        let password = Client::prompt_secret(client, "choose password: ");
+                        if Client::register_account(client, &url, &name, &password) {
+                            Client::connect_server(client, url, name, password);
+                        }
                     }
                     other => {
                         client.seal().writeln(&format!("'{}' is not available in this context", other.cmd_ident()));
@@ -88,14 +148,16 @@ pub(crate) fn handle_input(client: &ThreadClient) {
             Location::Lobby => {
                 match cmd {
                     Command::Info => {
-                        let c = client.seal();
+                        let mut c = client.seal();
                         let mut info = String::new();
                         info.push_str(&format!("client-version: {}\n", env!("CARGO_PKG_VERSION")));
                         info.push_str(&format!("location: {}\n", c.loc));
                         info.push_str(&format!("server: {}\n", c.server.as_ref().unwrap()));
                         info.push_str(&format!("server-version: {}\n", c.server_version.as_ref().unwrap()));
-                        info.push_str("\nJoin a chat with '/j <chat_id> <invite>'\nor create a new one with '/p <title>'");
-                        client.seal().writeln(info.trim_end());
+                        let protocol = c.protocol.as_ref().unwrap();
+                        info.push_str(&format!("protocol: {} {:?}\n", protocol.0, protocol.1));
+                        info.push_str("\nList public chats with '/j', join a chat with '/j <title or id> [invite]'\nor create a new one with '/p <title>'");
+                        c.writeln(info.trim_end());
                     }
                     Command::Quit => {
                         Client::disconnect_server(client);
@@ -103,8 +165,20 @@ pub(crate) fn handle_input(client: &ThreadClient) {
                     Command::CreateChat(title) => {
                         Client::send_ws_message(client, ClientWsMessage::ChatCreate(title));
                     }
-                    Command::Join(chat_id, invite_id) => {
-                        Client::send_ws_message(client, ClientWsMessage::ChatJoin(chat_id, invite_id));
+                    Command::Join(chat_title, invite_id) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatJoin(chat_title, invite_id));
+                    }
+                    Command::DirectMessage(name, content) => {
+                        Client::send_ws_message(client, ClientWsMessage::DirectMessage(name, content));
+                    }
+                    Command::Switch(title) => {
+                        switch_chat(client, title);
+                    }
+                    Command::Directory => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatDirectory);
+                    }
+                    Command::SetNick(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::SetNick(name));
                     }
                     other => {
                         client.seal().writeln(&format!("'{}' is not available in this context", other.cmd_ident()));
@@ -112,34 +186,115 @@ pub(crate) fn handle_input(client: &ThreadClient) {
                 }
             }
             Location::Chat => {
+                let chat_id = client.seal().chat_id.clone().unwrap();
                 match cmd {
                     Command::Info => {
-                        let c = client.seal();
+                        let mut c = client.seal();
                         let mut info = String::new();
                         info.push_str(&format!("client-version: {}\n", env!("CARGO_PKG_VERSION")));
                         info.push_str(&format!("location: {}\n", c.loc));
                         info.push_str(&format!("server: {}\n", c.server.as_ref().unwrap()));
                         info.push_str(&format!("server-version: {}\n", c.server_version.as_ref().unwrap()));
-                        info.push_str(&format!("chat: {}\n", c.chat_title.as_ref().unwrap()));
-                        info.push_str(&format!("is-admin: {}\n", c.is_admin));
+                        let protocol = c.protocol.as_ref().unwrap();
+                        info.push_str(&format!("protocol: {} {:?}\n", protocol.0, protocol.1));
+                        let chat = c.active_chat().unwrap();
+                        info.push_str(&format!("chat: {}\n", chat.title));
+                        info.push_str(&format!("is-admin: {}\n", chat.is_admin));
+                        info.push_str(&format!("joined-chats: {}\n", c.chats.len()));
                         c.writeln(info.trim_end());
                     }
                     Command::Quit => {
-                        Client::send_ws_message(client, ClientWsMessage::ChatLeave);
+                        Client::send_ws_message(client, ClientWsMessage::ChatLeave(chat_id.clone()));
                         let mut c = client.seal();
-                        c.writeln(&format!("Disconnected from chat {}", c.chat_title.as_ref().unwrap()));
+                        if let Some(chat) = c.chats.remove(&chat_id) {
+                            c.writeln(&format!("Disconnected from chat {}", chat.title));
+                        }
                         c.loc = Location::Lobby;
                         c.chat_id = None;
-                        c.chat_title = None;
+                    }
+                    Command::CreateChat(title) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatCreate(title));
+                    }
+                    Command::Join(chat_title, invite_id) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatJoin(chat_title, invite_id));
+                    }
+                    Command::Switch(title) => {
+                        switch_chat(client, title);
+                    }
+                    Command::Directory => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatDirectory);
+                    }
+                    Command::SetNick(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::SetNick(name));
                     }
                     Command::SendMessage(content) => {
-                        Client::send_ws_message(&client, ClientWsMessage::Message(content));
+                        // the server refuses messages over its limit, so long ones are sent in parts
+                        let parts = match &client.seal().rules {
+                            Some(rules) => rules.split_message(&content),
+                            None => vec![content]
+                        };
+                        if parts.len() > MAX_MESSAGE_PARTS {
+                            client.seal().writeln(&format!("Message is too long, it would have to be sent as {} messages", parts.len()));
+                        } else {
+                            for part in parts {
+                                Client::send_ws_message(&client, ClientWsMessage::Message(chat_id.clone(), part));
+                            }
+                        }
+                    }
+                    Command::DirectMessage(name, content) => {
+                        Client::send_ws_message(client, ClientWsMessage::DirectMessage(name, content));
+                    }
+                    Command::CreateInvite(uses, valid_for) => {
+                        Client::send_ws_message(&client, ClientWsMessage::ChatCreateInvite(chat_id, uses, valid_for));
                     }
-                    Command::CreateInvite => {
-                        Client::send_ws_message(&client, ClientWsMessage::ChatCreateInvite);
+                    Command::Invites(None) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatListInvites(chat_id));
+                    }
+                    Command::Invites(Some(invite_id)) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatRevokeInvite(chat_id, invite_id));
                     }
                     Command::ListMembers => {
-                        Client::send_ws_message(&client, ClientWsMessage::ChatListMembers);
+                        Client::send_ws_message(&client, ClientWsMessage::ChatListMembers(chat_id));
+                    }
+                    Command::History => {
+                        let before = client.seal().active_chat().unwrap().oldest_message;
+                        Client::send_ws_message(client, ClientWsMessage::ChatHistory(chat_id, before, HISTORY_PAGE));
+                    }
+                    Command::Kick(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatModerate(chat_id, name, ModAction::Kick));
+                    }
+                    Command::Ban(name, duration) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatModerate(chat_id, name, ModAction::Ban(duration)));
+                    }
+                    Command::Mute(name, duration) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatModerate(chat_id, name, ModAction::Mute(duration)));
+                    }
+                    Command::Pardon(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatModerate(chat_id, name, ModAction::Pardon));
+                    }
+                    Command::Upload(path) => {
+                        Client::upload_file(client, &chat_id, &path);
+                    }
+                    Command::Download(file_id) => {
+                        Client::download_file(client, &chat_id, file_id);
+                    }
+                    Command::ListFiles => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatListFiles(chat_id));
+                    }
+                    Command::Admin(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatSetRole(chat_id, name, Role::Admin));
+                    }
+                    Command::Demote(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatSetRole(chat_id, name, Role::Member));
+                    }
+                    Command::TransferOwner(name) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatSetRole(chat_id, name, Role::Owner));
+                    }
+                    Command::Topic(topic) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatSetTopic(chat_id, topic));
+                    }
+                    Command::SetVisibility(visibility) => {
+                        Client::send_ws_message(client, ClientWsMessage::ChatSetVisibility(chat_id, visibility));
                     }
                     other => {
                         client.seal().writeln(&format!("'{}' is not available in this context", other.cmd_ident()));
@@ -153,6 +308,53 @@ pub(crate) fn handle_input(client: &ThreadClient) {
     }
 }
 
+fn switch_chat(client: &ThreadClient, title: Option<ChatTitle>) {
+    let mut c = client.seal();
+    let title = match title {
+        Some(title) => title,
+        None => {
+            if c.chats.is_empty() {
+                c.writeln("You are not in any chat");
+                return;
+            }
+            let mut list = String::from("joined chats:\n");
+            for (chat_id, chat) in c.chats.iter() {
+                let active = if c.chat_id.as_ref() == Some(chat_id) { " (active)" } else { "" };
+                let unread = if chat.unread > 0 { format!(" ({} unread)", chat.unread) } else { String::new() };
+                list.push_str(&format!("    {}{}{}\n", chat.title, active, unread));
+            }
+            c.writeln(list.trim_end());
+            return;
+        }
+    };
+    let chat_id = match c.chats.iter().find(|(_, chat)| chat.title == title) {
+        Some((chat_id, _)) => chat_id.clone(),
+        None => {
+            c.writeln(&format!("You are not a member of chat {}", title));
+            return;
+        }
+    };
+    let history = c.supports(Feature::History);
+    let chat = c.chats.get_mut(&chat_id).unwrap();
+    let unread = std::mem::take(&mut chat.unread);
+    // show what was missed
+    let catch_up = unread > 0 && history;
+    chat.catching_up = catch_up;
+    c.chat_id = Some(chat_id.clone());
+    c.loc = Location::Chat;
+    c.writeln(&format!("Switched to chat {}", title));
+    drop(c);
+    if catch_up {
+        Client::send_ws_message(client, ClientWsMessage::ChatHistory(chat_id, None, unread));
+    }
+}
+
+// in seconds
+fn parse_minutes(minutes: &str) -> Result<Seconds, String> {
+    let minutes = minutes.parse::<u64>().map_err(|_| format!("'{}' is not a number of minutes", minutes))?;
+    minutes.checked_mul(60).ok_or_else(|| format!("{} minutes is too long", minutes))
+}
+
 fn parse_command(command: String) -> Result<Command, String> {
     macro_rules! invalid_command {
         () => {format!("Invalid command '{}'. Type '/?' for help", command)};
@@ -175,6 +377,27 @@ fn parse_command(command: String) -> Result<Command, String> {
                 args.remove(0)
             }
         }
+        // <name> [minutes]
+        macro_rules! name_and_minutes {
+            ($cmd: literal) => {
+                if args.len() != 1 && args.len() != 2 {
+                    Err(format!("Command /{} expects 1 or 2 args, found {}", $cmd, args.len()))
+                }
+                else {
+                    let duration = match args.get(1) {
+                        Some(m) => Some(parse_minutes(m)?),
+                        None => None
+                    };
+                    Ok((arg!(), duration))
+                }
+            }
+        }
+        // commands are single letters, only /nick has a long name next to /e
+        if args[0] == "/nick" {
+            args.remove(0);
+            args_len!(1, "nick")?;
+            return Ok(Command::SetNick(arg!()))
+        }
         if args.remove(0).len() > 2 {
             return Err(invalid_command!())
         }
@@ -186,31 +409,111 @@ fn parse_command(command: String) -> Result<Command, String> {
                     args_len!(2, 'c')?;
                     Ok(Command::Connect(arg!(), arg!()))
                 },
+                'r' => {
+                    args_len!(2, 'r')?;
+                    Ok(Command::Register(arg!(), arg!()))
+                },
                 'p' => {
                     args_len!(1, 'p')?;
                     Ok(Command::CreateChat(arg!()))
                 },
-                'j' => {
-                    args_len!(2, 'j')?;
-                    Ok(Command::Join(arg!(), arg!()))
+                'j' => match args.len() {
+                    0 => Ok(Command::Directory),
+                    1 => Ok(Command::Join(arg!(), None)),
+                    2 => Ok(Command::Join(arg!(), Some(arg!()))),
+                    n => Err(format!("Command /j expects 0 to 2 args, found {}", n))
                 },
                 'l' => Ok(Command::ListMembers),
                 'k' => {
                     args_len!(1, 'k')?;
                     Ok(Command::Kick(args.remove(0)))
                 },
+                'b' => {
+                    let (name, duration) = name_and_minutes!('b')?;
+                    Ok(Command::Ban(name, duration))
+                },
+                'm' => {
+                    let (name, duration) = name_and_minutes!('m')?;
+                    Ok(Command::Mute(name, duration))
+                },
+                'u' => {
+                    args_len!(1, 'u')?;
+                    Ok(Command::Pardon(arg!()))
+                },
                 'q' => Ok(Command::Quit),
                 'f' => {
                     args_len!(1, 'f')?;
                     Ok(Command::Upload(args.remove(0)))
                 },
+                'g' => {
+                    args_len!(1, 'g')?;
+                    let file_id = arg!();
+                    Ok(Command::Download(file_id.parse().map_err(|_| format!("'{}' is not a file id", file_id))?))
+                },
+                'a' => {
+                    args_len!(0, 'a')?;
+                    Ok(Command::ListFiles)
+                },
                 'y' => {
                     args_len!(1, 'y')?;
                     Ok(Command::Admin(args.remove(0)))
                 },
+                'd' => {
+                    args_len!(1, 'd')?;
+                    Ok(Command::Demote(arg!()))
+                },
+                'o' => {
+                    args_len!(1, 'o')?;
+                    Ok(Command::TransferOwner(arg!()))
+                },
+                'w' => {
+                    if args.len() < 2 {
+                        return Err(format!("Command /w expects a name and a message, found {} args", args.len()))
+                    }
+                    let name = arg!();
+                    Ok(Command::DirectMessage(name, args.join(" ")))
+                },
+                's' => match args.len() {
+                    0 => Ok(Command::Switch(None)),
+                    1 => Ok(Command::Switch(Some(arg!()))),
+                    n => Err(format!("Command /s expects 0 or 1 args, found {}", n))
+                },
+                't' => Ok(Command::Topic(if args.is_empty() { None } else { Some(args.join(" ")) })),
+                'v' => {
+                    args_len!(1, 'v')?;
+                    match arg!().as_str() {
+                        "public" => Ok(Command::SetVisibility(Visibility::Public)),
+                        "invite" => Ok(Command::SetVisibility(Visibility::InviteOnly)),
+                        other => Err(format!("'{}' is neither public nor invite", other))
+                    }
+                },
+                'e' => {
+                    args_len!(1, 'e')?;
+                    Ok(Command::SetNick(arg!()))
+                },
                 'n' => {
-                    args_len!(0, 'n')?;
-                    Ok(Command::CreateInvite)
+                    if args.len() > 2 {
+                        return Err(format!("Command /n expects 0 to 2 args, found {}", args.len()))
+                    }
+                    let uses = match args.first() {
+                        Some(uses) => Some(uses.parse::<u32>().map_err(|_| format!("'{}' is not a number of uses", uses))?),
+                        None => None
+                    };
+                    let valid_for = match args.get(1) {
+                        Some(m) => Some(parse_minutes(m)?),
+                        None => None
+                    };
+                    // single use unless told otherwise
+                    Ok(Command::CreateInvite(uses.or(Some(1)), valid_for))
+                },
+                'x' => match args.len() {
+                    0 => Ok(Command::Invites(None)),
+                    1 => Ok(Command::Invites(Some(arg!()))),
+                    n => Err(format!("Command /x expects 0 or 1 args, found {}", n))
+                },
+                'h' => {
+                    args_len!(0, 'h')?;
+                    Ok(Command::History)
                 },
                 _ => Err(invalid_command!())
             }
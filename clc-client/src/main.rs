@@ -7,6 +7,7 @@ mod client;
 mod input_handler;
 mod web_client;
 mod ws_client;
+mod tui;
 
 fn main() {
     let client = Client::new();
@@ -0,0 +1,130 @@
+use std::io::{stdout, Write};
+use std::panic;
+use std::process;
+use crossterm::{cursor, execute, queue, style, terminal};
+use crossterm::style::Attribute;
+use crossterm::terminal::ClearType;
+use crate::client::Client;
+use crate::web_client::Location;
+
+// used when the size can't be queried, e.g. when not attached to a terminal
+const FALLBACK_SIZE: (u16, u16) = (80, 24);
+
+pub(crate) fn init() {
+    let _ = terminal::enable_raw_mode();
+    let _ = execute!(stdout(), terminal::EnterAlternateScreen);
+    // leave the terminal usable when panicking
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+    }));
+}
+
+pub(crate) fn restore() {
+    let _ = execute!(stdout(), terminal::LeaveAlternateScreen, cursor::Show);
+    let _ = terminal::disable_raw_mode();
+}
+
+pub(crate) fn exit(code: i32) -> ! {
+    restore();
+    process::exit(code)
+}
+
+pub(crate) fn size() -> (u16, u16) {
+    match terminal::size() {
+        Ok((w, h)) if w > 0 && h > 2 => (w, h),
+        _ => FALLBACK_SIZE
+    }
+}
+
+// rows the message pane spans, the last two rows are the status bar and the input line
+pub(crate) fn pane_height() -> usize {
+    size().1 as usize - 2
+}
+
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let mut rows = vec![];
+    for part in line.split('\n') {
+        let chars: Vec<char> = part.chars().filter(|c| *c != '\r').collect();
+        if chars.is_empty() {
+            rows.push(String::new());
+        }
+        for chunk in chars.chunks(width) {
+            rows.push(chunk.iter().collect());
+        }
+    }
+    rows
+}
+
+fn status(client: &Client) -> String {
+    let mut status = match (&client.name, &client.server) {
+        (Some(name), Some(server)) => format!(" {}@{}", name, server),
+        _ => String::from(" not connected")
+    };
+    if let Location::Chat = client.loc {
+        if let Some(chat) = client.active_chat() {
+            status.push_str(&format!(" | {}{}", chat.title, if chat.is_admin { " (admin)" } else { "" }));
+        }
+    }
+    if !client.chats.is_empty() {
+        let unread: usize = client.chats.values().map(|chat| chat.unread).sum();
+        status.push_str(&format!(" | {} chats", client.chats.len()));
+        if unread > 0 {
+            status.push_str(&format!(", {} unread", unread));
+        }
+    }
+    if client.scroll > 0 {
+        status.push_str(&format!(" | scrolled up {} lines", client.scroll));
+    }
+    status
+}
+
+pub(crate) fn draw(client: &mut Client) {
+    let (w, h) = size();
+    let width = w as usize;
+    let height = h as usize - 2;
+
+    // collect rows bottom up, only wrapping as many lines as can be shown
+    let mut rows = vec![];
+    for line in client.lines.iter().rev() {
+        rows.extend(wrap(line, width).into_iter().rev());
+        if rows.len() >= height + client.scroll {
+            break;
+        }
+    }
+    client.scroll = client.scroll.min(rows.len().saturating_sub(height));
+    let visible: Vec<&String> = rows.iter().skip(client.scroll).take(height).collect();
+
+    let mut out = stdout();
+    let _ = queue!(out, cursor::Hide);
+    for y in 0..height {
+        // messages stick to the bottom of the pane
+        let row = visible.get(height - 1 - y);
+        let _ = queue!(out, cursor::MoveTo(0, y as u16), terminal::Clear(ClearType::CurrentLine));
+        if let Some(row) = row {
+            let _ = queue!(out, style::Print(row));
+        }
+    }
+
+    let status: String = status(client).chars().take(width).collect();
+    let _ = queue!(out,
+        cursor::MoveTo(0, height as u16),
+        style::SetAttribute(Attribute::Reverse),
+        style::Print(format!("{:1$}", status, width)),
+        style::SetAttribute(Attribute::Reset)
+    );
+
+    let input = if client.mask_input { "*".repeat(client.input.chars().count()) } else { client.input.clone() };
+    let line: Vec<char> = format!("{}{}", client.prompt, input).chars().collect();
+    // keep the end of long input visible
+    let start = line.len().saturating_sub(width - 1);
+    let line: String = line[start..].iter().collect();
+    let _ = queue!(out,
+        cursor::MoveTo(0, height as u16 + 1),
+        terminal::Clear(ClearType::CurrentLine),
+        style::Print(&line),
+        cursor::Show
+    );
+    let _ = out.flush();
+}
@@ -1,29 +1,99 @@
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use tungstenite::Message;
 use clc_lib::{deserialize, serialize};
-use clc_lib::protocol::{ChatTitle, ClientWsMessage, Response, ServerConnectRequest, ServerConnectResponse, ServerDisconnectRequest, ServerDisconnectResponse, ServerUrl, UserName};
+use clc_lib::validator::Rules;
+use clc_lib::protocol::{ChatId, ClientWsMessage, ClientWsRequest, Feature, FileId, FilePath, Handshake, Password, Response, ServerConnectRequest, ServerConnectResponse, ServerDisconnectRequest, ServerDisconnectResponse, ServerRegisterRequest, ServerRegisterResponse, ServerUploadResponse, ServerUrl, ServerVersion, UserName};
 use crate::Client;
 use crate::client::{ClientSeal, ThreadClient};
 use crate::ws_client::create_ws_connection;
 
+// path to a pem certificate that is trusted in addition to the system ones, e.g. a self signed one
+const CA_CERT_VAR: &str = "CLC_CA_CERT";
+// directory downloaded files are written to, defaults to ./downloads
+const DOWNLOAD_DIR_VAR: &str = "CLC_DOWNLOAD_DIR";
+
 enum Method {
     Get,
     Post,
     Delete
 }
 
+// everything this client knows how to handle
+fn handshake() -> Handshake {
+    Handshake::new(vec![Feature::History, Feature::Files, Feature::DirectMessages])
+}
+
 impl Client {
-    pub(crate) fn connect_server(client: &ThreadClient, url: ServerUrl, name: UserName) {
-        match Self::request(Method::Post, format!("https://{}/api/register", url), &ServerConnectRequest(name.clone())) {
-            Ok(Response::Accept(ServerConnectResponse(uuid, version))) => {
+    // servers from before the handshake would answer with something that can't be parsed
+    fn check_compatibility(url: &ServerUrl) -> Result<Rules, String> {
+        let res = http_client()?.get(api_url(url, "version")).send().map_err(|e| format!("{}", e))?;
+        let txt = res.text().map_err(|e| format!("{}", e))?;
+        let ServerVersion(_version, server, rules) = deserialize(&txt).map_err(|_| "incompatible server".to_string())?;
+        match handshake().agree(&server) {
+            Some(_) => Ok(rules),
+            None => Err(Handshake::refusal(&handshake(), &server))
+        }
+    }
+
+    // the same checks the server does, so the user doesn't have to wait for a reply
+    fn check_input(rules: &Rules, message: &ClientWsMessage) -> Result<(), String> {
+        match message {
+            ClientWsMessage::ChatCreate(title) => rules.check_title(title).map_err(|e| format!("title {}", e)),
+            ClientWsMessage::SetNick(name) => rules.check_name(name).map_err(|e| format!("name {}", e)),
+            ClientWsMessage::ChatSetTopic(_, Some(topic)) => rules.check_topic(topic).map_err(|e| format!("topic {}", e)),
+            ClientWsMessage::Message(_, content) | ClientWsMessage::DirectMessage(_, content) => rules.check_message(content).map_err(|e| format!("message {}", e)),
+            _ => Ok(())
+        }
+    }
+
+    pub(crate) fn register_account(client: &ThreadClient, url: &ServerUrl, name: &UserName, password: &Password) -> bool {
+        let checked = Self::check_compatibility(url).and_then(|rules| {
+            rules.check_name(name).map_err(|e| format!("name {}", e))?;
+            rules.check_password(password).map_err(|e| format!("password {}", e))
+        });
+        if let Err(e) = checked {
+            client.seal().writeln(&format!("Unable to create account {} on server {}: {}", name, url, e));
+            return false;
+        }
+        match Self::request(Method::Post, api_url(url, "register"), &ServerRegisterRequest(name.clone(), password.clone(), handshake())) {
+            Ok(Response::Accept(ServerRegisterResponse(_uuid))) => {
+                client.seal().writeln(&format!("Created account {} on server {}", name, url));
+                true
+            }
+            Ok(Response::Fail(reason)) => {
+                client.seal().writeln(&format!("Error: {}", reason));
+                false
+            }
+            Err(e) => {
+                client.seal().writeln(&format!("Unable to create account {} on server {}: {}", name, url, e));
+                false
+            }
+        }
+    }
+
+    pub(crate) fn connect_server(client: &ThreadClient, url: ServerUrl, name: UserName, password: Password) {
+        let rules = match Self::check_compatibility(&url) {
+            Ok(rules) => rules,
+            Err(e) => {
+                client.seal().writeln(&format!("Unable to connect to server {} as {}: {}", url, name, e));
+                return;
+            }
+        };
+        match Self::request(Method::Post, api_url(&url, "login"), &ServerConnectRequest(name.clone(), password, handshake())) {
+            Ok(Response::Accept(ServerConnectResponse(uuid, session, shown_name, version, protocol))) => {
                 {
                     let mut c = client.seal();
                     c.server = Some(url.clone());
-                    c.name = Some(name.clone());
+                    c.name = Some(shown_name);
                     c.user_id = Some(uuid);
+                    c.session = Some(session);
                     c.loc = Location::Lobby;
                     c.server_version = Some(version);
+                    c.protocol = Some(protocol);
+                    c.rules = Some(rules);
                     c.writeln(&format!("Connected to server {} as {}", url, name));
                 }
                 create_ws_connection(client);
@@ -37,22 +107,20 @@ impl Client {
 
     pub(crate) fn disconnect_server(client: &ThreadClient) {
         let url = client.seal().server.as_ref().unwrap().clone();
-        let user_id = client.seal().user_id.as_ref().unwrap().clone();
-        match Self::request(Method::Delete, format!("https://{}/api/register", url), &ServerDisconnectRequest(user_id)) {
+        let session = client.seal().session.as_ref().unwrap().clone();
+        match Self::request(Method::Delete, api_url(&url, "login"), &ServerDisconnectRequest(session)) {
             Ok(Response::Accept(ServerDisconnectResponse())) => {
+                // the socket thread writes to the client as well, so it is joined without holding the lock
+                let (socket, sender) = {
+                    let mut c = client.seal();
+                    (c.socket.take(), c.sender.take())
+                };
+                if let (Some(socket), Some(sender)) = (socket, sender) {
+                    let _ = sender.send(Message::Close(None));
+                    socket.join().expect("Unable to join ws thread");
+                }
                 let mut c = client.seal();
-                c.server = None;
-                c.user_id = None;
-                c.name = None;
-                c.chat_id = None;
-                c.chat_title = None;
-                c.server_version = None;
-                let _ = c.socket.take().map(|s| {
-                    let _ = c.sender.as_ref().unwrap().send(Message::Close(None));
-                    s.join().expect("Unable to join ws thread");
-                });
-                c.sender = None;
-                c.loc = Location::Home;
+                c.reset_connection();
                 c.writeln(&format!("Disconnected from server {}", url));
             }
             Ok(Response::Fail(reason)) => client.seal().writeln(&format!("Error: {}", reason)),
@@ -62,12 +130,91 @@ impl Client {
         }
     }
 
+    pub(crate) fn upload_file(client: &ThreadClient, chat_id: &ChatId, path: &FilePath) {
+        let (url, session) = {
+            let c = client.seal();
+            (c.server.as_ref().unwrap().clone(), c.session.as_ref().unwrap().clone())
+        };
+        let name = match Path::new(path).file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                client.seal().writeln(&format!("{} is not a file", path));
+                return;
+            }
+        };
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) => {
+                client.seal().writeln(&format!("Unable to read {}: {}", path, e));
+                return;
+            }
+        };
+        let result = http_client().and_then(|http| {
+            let res = http.post(api_url(&url, &format!("files/{}", chat_id)))
+                .bearer_auth(&session)
+                .query(&[("name", &name)])
+                .body(content)
+                .send().map_err(|e| format!("{}", e))?;
+            if !res.status().is_success() {
+                return Err(format!("server responded with {}", res.status()));
+            }
+            deserialize::<Response<ServerUploadResponse>>(&res.text().map_err(|e| format!("{}", e))?)
+        });
+        match result {
+            // the server announces the file to the whole chat
+            Ok(Response::Accept(ServerUploadResponse(_file_id))) => {}
+            Ok(Response::Fail(reason)) => client.seal().writeln(&format!("Error: {}", reason)),
+            Err(e) => client.seal().writeln(&format!("Unable to upload {}: {}", path, e))
+        }
+    }
+
+    pub(crate) fn download_file(client: &ThreadClient, chat_id: &ChatId, file_id: FileId) {
+        let (url, session) = {
+            let c = client.seal();
+            (c.server.as_ref().unwrap().clone(), c.session.as_ref().unwrap().clone())
+        };
+        let result = http_client().and_then(|http| {
+            let res = http.get(api_url(&url, &format!("files/{}/{}", chat_id, file_id)))
+                .bearer_auth(&session)
+                .send().map_err(|e| format!("{}", e))?;
+            if !res.status().is_success() {
+                return Err(format!("file {} does not exist in this chat", file_id));
+            }
+            let name = res.headers().get(reqwest::header::CONTENT_DISPOSITION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(disposition_file_name)
+                .unwrap_or_else(|| file_id.to_string());
+            let content = res.bytes().map_err(|e| format!("{}", e))?;
+            let path = download_path(&name, file_id);
+            fs::create_dir_all(path.parent().unwrap()).map_err(|e| format!("{}", e))?;
+            fs::write(&path, content).map_err(|e| format!("{}", e))?;
+            Ok(path)
+        });
+        match result {
+            Ok(path) => client.seal().writeln(&format!("Saved file {} to {}", file_id, path.display())),
+            Err(e) => client.seal().writeln(&format!("Unable to download file {}: {}", file_id, e))
+        }
+    }
+
     pub(crate) fn send_ws_message(client: &ThreadClient, message: ClientWsMessage){
-        client.seal().sender.as_ref().unwrap().send(Message::Text(serialize(&message).expect("Unable to serialize"))).expect("Unable to send message");
+        let mut c = client.seal();
+        if let Some(Err(e)) = c.rules.as_ref().map(|rules| Self::check_input(rules, &message)) {
+            c.writeln(&format!("Error: {}", e));
+            return;
+        }
+        let request_id = c.next_request;
+        c.next_request += 1;
+        let request = serialize(&ClientWsRequest(request_id, message.clone())).expect("Unable to serialize");
+        let sent = c.sender.as_ref().is_some_and(|sender| sender.send(Message::Text(request)).is_ok());
+        if sent {
+            c.pending.insert(request_id, message);
+        } else {
+            c.writeln("Not connected, the websocket is closed");
+        }
     }
 
     fn request<B: Serialize, R: for<'a> Deserialize<'a>>(method: Method, url: String, body: &B) -> Result<R, String>{
-        let client = reqwest::blocking::Client::new();
+        let client = http_client()?;
         let req = match method {
             Method::Get => client.get(url),
             Method::Post => client.post(url),
@@ -79,6 +226,60 @@ impl Client {
     }
 }
 
+fn http_client() -> Result<reqwest::blocking::Client, String> {
+    let mut builder = reqwest::blocking::Client::builder();
+    if let Some(cert) = ca_cert()? {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&cert).map_err(|e| format!("{}", e))?);
+    }
+    builder.build().map_err(|e| format!("{}", e))
+}
+
+// attachment; filename="<name>"
+fn disposition_file_name(header: &str) -> Option<String> {
+    let name = header.split("filename=").nth(1)?.trim().trim_matches('"');
+    // never trust a server with paths
+    let name = name.rsplit(['/', '\\']).next()?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// existing files are not overwritten, the file id is prepended instead
+fn download_path(name: &str, file_id: FileId) -> PathBuf {
+    let dir = PathBuf::from(std::env::var(DOWNLOAD_DIR_VAR).unwrap_or_else(|_| "downloads".to_string()));
+    let path = dir.join(name);
+    if path.exists() {
+        dir.join(format!("{}-{}", file_id, name))
+    } else {
+        path
+    }
+}
+
+pub(crate) fn ca_cert() -> Result<Option<Vec<u8>>, String> {
+    match std::env::var(CA_CERT_VAR) {
+        Ok(path) => fs::read(&path).map(Some).map_err(|e| format!("Unable to read {}: {}", path, e)),
+        Err(_) => Ok(None)
+    }
+}
+
+// servers are contacted over tls unless the url explicitly starts with http://
+fn is_plaintext(url: &ServerUrl) -> bool {
+    url.starts_with("http://")
+}
+
+fn host(url: &ServerUrl) -> &str {
+    url.trim_start_matches("http://").trim_start_matches("https://").trim_end_matches('/')
+}
+
+fn api_url(url: &ServerUrl, path: &str) -> String {
+    format!("{}://{}/api/{}", if is_plaintext(url) { "http" } else { "https" }, host(url), path)
+}
+
+pub(crate) fn ws_url(url: &ServerUrl, path: &str) -> String {
+    format!("{}://{}/ws/{}", if is_plaintext(url) { "ws" } else { "wss" }, host(url), path)
+}
+
 #[derive(Clone)]
 pub(crate) enum Location {
     Home,
@@ -1,118 +1,371 @@
-use std::net::TcpStream;
-use std::sync::mpsc::channel;
+use futures_util::{SinkExt, StreamExt};
+use native_tls::{Certificate, TlsConnector};
 use std::thread;
-use native_tls::{TlsConnector, TlsConnectorBuilder, TlsStream};
-use reqwest::Url;
-use tungstenite::{accept, connect, Message};
-use tungstenite::client::client as create_client;
-use tungstenite::stream::MaybeTlsStream;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+use tungstenite::http::StatusCode;
 use clc_lib::deserialize;
-use clc_lib::protocol::{ServerEvent, ServerWsMessage};
-use crate::client::{ClientSeal, ThreadClient};
-use crate::web_client::Location;
+use clc_lib::protocol::{ChatMessage, ClientWsMessage, FileInfo, InviteInfo, ModAction, PublicChat, Role, ServerEvent, ServerWsMessage};
+use crate::client::{ClientSeal, JoinedChat, ThreadClient};
+use crate::web_client::{ca_cert, ws_url, Location};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// attempts to reopen a dropped websocket before giving up, the server keeps the session for a while
+const RECONNECT_ATTEMPTS: u32 = 10;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// the server is pinged when it was quiet for a while and counts as gone when it stays quiet
+const KEEPALIVE: Duration = Duration::from_secs(20);
+const SERVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum Closed {
+    // the user disconnected or the client dropped the sender
+    ByUser,
+    Dropped
+}
 
 pub(crate) fn create_ws_connection(client: &ThreadClient){
     let url = {
         let c = client.seal();
-        &format!("wss://{}/ws/{}", c.server.as_ref().unwrap(), c.user_id.as_ref().unwrap())
+        ws_url(c.server.as_ref().unwrap(), c.session.as_ref().unwrap())
     };
-    println!("OOOOOO");
-    let (mut socket, _response) = connect(url).expect("Can't connect");
-    match socket.get_mut() {
-        MaybeTlsStream::NativeTls(stream) => {
-            stream.get_mut().set_nonblocking(true).expect("Unable to set nonblocking");
+    // the socket gets its own single threaded runtime, the rest of the client stays blocking
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            client.seal().writeln(&format!("Unable to start websocket runtime: {}", e));
+            return;
         }
-        _ => unreachable!()
-    }
-    println!("222222");
+    };
+    let connector = match connector(&url) {
+        Ok(connector) => connector,
+        Err(e) => {
+            client.seal().writeln(&format!("Unable to open websocket: {}", e));
+            return;
+        }
+    };
+    let socket = match runtime.block_on(open_socket(&url, &connector)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            client.seal().writeln(&format!("Unable to open websocket: {}", e));
+            return;
+        }
+    };
     let ws_client = client.clone();
-    let (tx, rx) = channel();
-    let socket_thread = thread::spawn(move || {
+    let (tx, rx) = unbounded_channel();
+    let socket_thread = thread::spawn(move || run_socket(runtime, socket, url, connector, rx, ws_client));
+    {
+        let mut c = client.seal();
+        c.socket = Some(socket_thread);
+        c.sender = Some(tx);
+        c.writeln("Created websocket connection");
+    }
+}
+
+// keeps the websocket open until the user disconnects, reconnecting when it drops
+fn run_socket(runtime: Runtime, socket: Socket, url: String, connector: Connector, mut rx: UnboundedReceiver<Message>, client: ThreadClient) {
+    runtime.block_on(async move {
+        // messages entered while the connection was down
+        let mut queued = vec![];
+        let mut socket = socket;
         loop {
-            // === receive message from client and send to server ===
-            let message = match rx.recv() {
-                Ok(Message::Close(f)) => {
-                    ws_client.seal().writeln(&format!("Websocket closed"));
-                    let _ = socket.write_message(Message::Close(f));
-                    return;
-                }
-                Ok(m) => m,
-                Err(e) => {
-                    ws_client.seal().writeln(&format!("Websocket thread error: {}", e));
+            if let Closed::ByUser = drive_socket(socket, &mut rx, &mut queued, &client).await {
+                return;
+            }
+            client.seal().writeln("Connection lost, reconnecting");
+            socket = match reconnect(&url, &connector, &mut rx, &mut queued, &client).await {
+                Ok(socket) => socket,
+                Err(Closed::ByUser) => return,
+                Err(Closed::Dropped) => {
+                    let mut c = client.seal();
+                    c.reset_connection();
+                    c.writeln("Session expired, connect again with /c");
                     return;
                 }
             };
-            match socket.write_message(message) {
-                Ok(()) => (),
-                Err(e) => {
-                    ws_client.seal().writeln(&format!("Websocket send error: {:?}", e));
-                    let _ = socket.write_message(Message::Close(None));
-                    return;
+            client.seal().writeln("Reconnected");
+        }
+    });
+}
+
+// sends what the user enters and shows what the server sends, whichever comes first
+async fn drive_socket(socket: Socket, rx: &mut UnboundedReceiver<Message>, queued: &mut Vec<Message>, client: &ThreadClient) -> Closed {
+    let (mut write, mut read) = socket.split();
+    while !queued.is_empty() {
+        let message = queued.remove(0);
+        if let Err(e) = write.send(message.clone()).await {
+            queued.insert(0, message);
+            client.seal().writeln(&format!("Websocket send error: {}", e));
+            return Closed::Dropped;
+        }
+    }
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+    keepalive.tick().await;
+    let mut last_heard = Instant::now();
+    loop {
+        tokio::select! {
+            // === check the server is still there ===
+            _ = keepalive.tick() => {
+                if last_heard.elapsed() >= SERVER_TIMEOUT {
+                    client.seal().writeln("Server stopped responding");
+                    return Closed::Dropped;
+                }
+                if last_heard.elapsed() >= KEEPALIVE {
+                    if let Err(e) = write.send(Message::Ping(Vec::new())).await {
+                        client.seal().writeln(&format!("Websocket send error: {}", e));
+                        return Closed::Dropped;
+                    }
                 }
             }
-
+            // === receive message from client and send to server ===
+            message = rx.recv() => match message {
+                Some(Message::Close(frame)) => {
+                    let _ = write.send(Message::Close(frame)).await;
+                    client.seal().writeln("Websocket closed");
+                    return Closed::ByUser;
+                }
+                Some(message) => {
+                    if let Err(e) = write.send(message.clone()).await {
+                        queued.push(message);
+                        client.seal().writeln(&format!("Websocket send error: {}", e));
+                        return Closed::Dropped;
+                    }
+                }
+                None => return Closed::ByUser
+            },
             // === receive message from server ===
-            match socket.read_message() {
-                Err(_) => {} // no message on nonblocking
-                Ok(message) => {
-                    match message {
-                        Message::Text(content) => {
-                            let msg = deserialize(&content).unwrap();
-                            receive_ws_message(msg, &ws_client);
-                        }
-                        Message::Binary(_) => panic!("Unsupported"),
-                        Message::Ping(data) => { let _ = socket.write_message(Message::Pong(data)); },
-                        Message::Pong(_) => {/* ponged */}
-                        Message::Close(_) => {
-                            let _ = socket.write_message(message);
-                            ws_client.seal().writeln(&format!("Websocket send_thread closed"));
-                            return;
-                        }
-                        Message::Frame(_) => unreachable!("Docs say this is unobtainable with reading")
+            message = read.next() => {
+                // anything the server sends shows it is still there
+                last_heard = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(content))) => match deserialize(&content) {
+                        Ok(message) => receive_ws_message(message, client),
+                        Err(e) => client.seal().writeln(&format!("Invalid message from server: {}", e))
+                    },
+                    // pings are answered by tungstenite, files are transferred over http
+                    Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                    Some(Ok(Message::Close(_))) | None => {
+                        client.seal().writeln("Websocket closed by server");
+                        return Closed::Dropped;
+                    }
+                    Some(Err(e)) => {
+                        client.seal().writeln(&format!("Websocket receive error: {}", e));
+                        return Closed::Dropped;
                     }
                 }
             }
         }
-    });
-    {
-        let mut c = client.seal();
-        c.socket = Some(socket_thread);
-        c.sender = Some(tx);
-        c.writeln("Created websocket connection");
     }
 }
 
+// retries with exponential backoff, the server rejects the token once the session is gone
+async fn reconnect(url: &str, connector: &Connector, rx: &mut UnboundedReceiver<Message>, queued: &mut Vec<Message>, client: &ThreadClient) -> Result<Socket, Closed> {
+    let mut backoff = Duration::from_secs(1);
+    for _ in 0..RECONNECT_ATTEMPTS {
+        let wait = tokio::time::sleep(backoff);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                message = rx.recv() => match message {
+                    Some(Message::Close(_)) | None => return Err(Closed::ByUser),
+                    Some(message) => queued.push(message)
+                }
+            }
+        }
+        match open_socket(url, connector).await {
+            Ok(socket) => return Ok(socket),
+            Err(tungstenite::Error::Http(response)) if response.status() == StatusCode::NOT_FOUND => return Err(Closed::Dropped),
+            // the server did not notice the old connection is gone yet
+            Err(tungstenite::Error::Http(response)) if response.status() == StatusCode::CONFLICT => client.seal().writeln("Server still holds the old connection, retrying"),
+            Err(e) => client.seal().writeln(&format!("Unable to reconnect: {}", e))
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    Err(Closed::Dropped)
+}
+
+fn connector(url: &str) -> Result<Connector, String> {
+    if url.starts_with("wss://") {
+        let mut builder = TlsConnector::builder();
+        if let Some(cert) = ca_cert()? {
+            builder.add_root_certificate(Certificate::from_pem(&cert).map_err(|e| format!("{}", e))?);
+        }
+        Ok(Connector::NativeTls(builder.build().map_err(|e| format!("{}", e))?))
+    } else {
+        Ok(Connector::Plain)
+    }
+}
+
+async fn open_socket(url: &str, connector: &Connector) -> Result<Socket, tungstenite::Error> {
+    let (socket, _response) = connect_async_tls_with_config(url, None, Some(connector.clone())).await?;
+    Ok(socket)
+}
+
 pub(crate) fn receive_ws_message(message: ServerWsMessage, client: &ThreadClient){
     match message {
-        ServerWsMessage::Message(_sender_id, sender, content) => client.seal().writeln(&format!("[{}]: {}", sender, content)),
+        ServerWsMessage::Message(chat_id, _sender_id, sender, content) => {
+            let mut c = client.seal();
+            if c.chat_id.as_ref() == Some(&chat_id) {
+                c.writeln(&format!("[{}]: {}", sender, content));
+            }
+            else if let Some(chat) = c.chats.get_mut(&chat_id) {
+                chat.unread += 1;
+                // only announce the first one, /s shows how many there are
+                if chat.unread == 1 {
+                    let title = chat.title.clone();
+                    c.writeln(&format!("New messages in {}, switch to it with /s {}", title, title));
+                }
+            }
+        }
         ServerWsMessage::SystemMessage(content) => client.seal().writeln(&content),
+        ServerWsMessage::ChatSystemMessage(chat_id, content) => client.seal().writeln_chat(&chat_id, &content),
+        ServerWsMessage::DirectMessage(_sender_id, sender, recipient, content) => client.seal().writeln(&format!("[{} -> {}]: {}", sender, recipient, content)),
+        ServerWsMessage::ChatDirectory(chats) => {
+            let mut c = client.seal();
+            if chats.is_empty() {
+                c.writeln("There are no public chats");
+                return;
+            }
+            let mut list = String::from("public chats:\n");
+            for PublicChat(_chat_id, title, members, topic) in chats {
+                let topic = topic.map(|topic| format!(": {}", topic)).unwrap_or_default();
+                list.push_str(&format!("    {} ({} members){}\n", title, members, topic));
+            }
+            c.writeln(list.trim_end());
+        }
+        ServerWsMessage::Invites(chat_id, invites) => {
+            let mut c = client.seal();
+            if invites.is_empty() {
+                c.writeln_chat(&chat_id, "There are no open invites");
+                return;
+            }
+            let mut list = String::from("open invites:\n");
+            for InviteInfo(invite_id, creator, uses_left, expires_in) in invites {
+                let uses = uses_left.map(|uses| format!("{} uses left", uses)).unwrap_or_else(|| "unlimited uses".to_string());
+                let expires = expires_in.map(|secs| format!("expires in {} minutes", secs / 60)).unwrap_or_else(|| "never expires".to_string());
+                list.push_str(&format!("    {} from {}, {}, {}\n", invite_id, creator, uses, expires));
+            }
+            c.writeln_chat(&chat_id, list.trim_end());
+        }
+        ServerWsMessage::Reply(request_id, result) => {
+            let mut c = client.seal();
+            let request = c.pending.remove(&request_id);
+            if let Err(error) = result {
+                let line = match &request {
+                    Some(ClientWsMessage::ChatCreate(title)) => format!("Unable to create chat {}: {}", title, error),
+                    Some(ClientWsMessage::ChatJoin(title, _)) => format!("Unable to join chat {}: {}", title, error),
+                    Some(ClientWsMessage::DirectMessage(name, _)) => format!("Unable to message {}: {}", name, error),
+                    _ => format!("Error: {}", error)
+                };
+                match request.as_ref().and_then(|request| request.chat_id()) {
+                    Some(chat_id) => c.writeln_chat(chat_id, &line),
+                    None => c.writeln(&line)
+                }
+            }
+        }
+        ServerWsMessage::History(chat_id, messages) => {
+            let mut c = client.seal();
+            if let Some(chat) = c.chats.get_mut(&chat_id) {
+                // the unread messages are newer than what was shown before, /h goes on from where it was
+                let catch_up = std::mem::take(&mut chat.catching_up);
+                match messages.first() {
+                    Some(ChatMessage(oldest, ..)) if !catch_up => chat.oldest_message = Some(*oldest),
+                    _ => {}
+                }
+            }
+            if messages.is_empty() {
+                c.writeln_chat(&chat_id, "No earlier messages");
+            }
+            for ChatMessage(_message_id, _sender_id, sender, content) in messages {
+                c.writeln_chat(&chat_id, &format!("[{}]: {}", sender, content));
+            }
+        }
         ServerWsMessage::SystemEvent(event) => match event {
             ServerEvent::ChatAccept(chat_id, chat_title) => {
                 let mut c = client.seal();
                 c.writeln(&format!("Joined chat {}", chat_title));
+                c.chats.insert(chat_id.clone(), JoinedChat::new(chat_title, false));
                 c.chat_id = Some(chat_id);
-                c.chat_title = Some(chat_title);
-                c.is_admin = false;
                 c.loc = Location::Chat;
             }
             ServerEvent::ChatCreate(chat_id, chat_title) => {
                 let mut c = client.seal();
                 c.writeln(&format!("Created chat {}", chat_title));
+                c.chats.insert(chat_id.clone(), JoinedChat::new(chat_title, true));
                 c.chat_id = Some(chat_id);
-                c.chat_title = Some(chat_title);
-                c.is_admin = true;
                 c.loc = Location::Chat;
             }
-            ServerEvent::SetAdmin(is_admin) => {
+            // known chats are resumed after reconnecting, unknown ones after logging in again
+            ServerEvent::ChatResumed(chat_id, chat_title, is_admin) => {
+                let mut c = client.seal();
+                match c.chats.get_mut(&chat_id) {
+                    Some(chat) => chat.is_admin = is_admin,
+                    None => {
+                        c.writeln(&format!("You are in chat {}, switch to it with /s {}", chat_title, chat_title));
+                        c.chats.insert(chat_id, JoinedChat::new(chat_title, is_admin));
+                    }
+                }
+            }
+            ServerEvent::ChatLeave(chat_id) => {
+                let mut c = client.seal();
+                if let Some(chat) = c.chats.remove(&chat_id) {
+                    c.writeln(&format!("You were removed from chat {}", chat.title));
+                    if c.chat_id.as_ref() == Some(&chat_id) {
+                        c.loc = Location::Lobby;
+                        c.chat_id = None;
+                    }
+                }
+            }
+            ServerEvent::Moderated(chat_id, name, action) => {
+                let duration = |secs: Option<u64>| match secs {
+                    Some(secs) => format!(" for {} minutes", secs / 60),
+                    None => String::new()
+                };
+                client.seal().writeln_chat(&chat_id, &match action {
+                    ModAction::Kick => format!("{} was kicked", name),
+                    ModAction::Ban(secs) => format!("{} was banned{}", name, duration(secs)),
+                    ModAction::Mute(secs) => format!("{} was muted{}", name, duration(secs)),
+                    ModAction::Pardon => format!("{} was pardoned", name)
+                });
+            }
+            ServerEvent::RoleChanged(chat_id, name, role) => {
+                let mut c = client.seal();
+                // SetAdmin already tells the user about their own role
+                if c.name.as_ref() != Some(&name) {
+                    c.writeln_chat(&chat_id, &match role {
+                        Role::Owner => format!("{} is now owner of this chat", name),
+                        Role::Admin => format!("{} is now admin of this chat", name),
+                        Role::Member => format!("{} is no longer admin of this chat", name)
+                    });
+                }
+            }
+            ServerEvent::NickChanged(user_id, old, new) => {
+                let mut c = client.seal();
+                if c.user_id.as_ref() == Some(&user_id) {
+                    c.name = Some(new.clone());
+                }
+                c.writeln(&format!("{} is now known as {}", old, new));
+            }
+            ServerEvent::FileShared(chat_id, FileInfo(file_id, name, size, uploader)) => {
+                client.seal().writeln_chat(&chat_id, &format!("{} shared {} ({} bytes), download it with /g {}", uploader, name, size, file_id));
+            }
+            ServerEvent::SetAdmin(chat_id, is_admin) => {
                 let mut c = client.seal();
                 if is_admin {
-                    c.writeln("You are now admin of this chat");
+                    c.writeln_chat(&chat_id, "You are now admin of this chat");
                 }
                 else {
-                    c.writeln("You are no longer admin of this chat");
+                    c.writeln_chat(&chat_id, "You are no longer admin of this chat");
+                }
+                if let Some(chat) = c.chats.get_mut(&chat_id) {
+                    chat.is_admin = is_admin;
                 }
-                c.is_admin = is_admin;
             }
         }
     }
-}
\ No newline at end of file
+}
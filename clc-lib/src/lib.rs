@@ -20,8 +20,6 @@ pub fn deserialize<T: for<'a> Deserialize<'a>>(s: &str) -> Result<T, String>{
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn tesu() {
 
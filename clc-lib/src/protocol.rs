@@ -1,15 +1,30 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use serde::{Serialize, Deserialize};
+use crate::validator::{Invalid, Rules};
 
 pub type UserName = String;
 pub type UserId = String;
+pub type Password = String;
+pub type SessionToken = String;
 pub type ChatTitle = String;
 pub type ChatId = String;
 pub type InviteId = String;
 pub type ServerUrl = String;
 pub type FilePath = String;
+pub type FileName = String;
 pub type Version = String;
 pub type Reason = String;
+pub type MessageId = u64;
+pub type Seconds = u64;
+pub type FileId = u64;
+pub type RequestId = u64;
+pub type ProtocolVersion = u32;
+
+// bumped whenever requests or messages change incompatibly, independent of the crate versions,
+// the handshakes in clc-client/testing/curl.md have to be bumped with it
+pub const PROTOCOL_VERSION: ProtocolVersion = 12;
+// the oldest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = 12;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response<T> {
@@ -17,38 +32,285 @@ pub enum Response<T> {
     Fail(Reason)
 }
 
+// optional parts of the protocol, only used when both sides support them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    History,
+    Files,
+    DirectMessages
+}
+
+// the protocol versions (min, max) and features one side supports
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake(pub ProtocolVersion, pub ProtocolVersion, pub Vec<Feature>);
+
+// what both sides agreed on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated(pub ProtocolVersion, pub Vec<Feature>);
+
+impl Handshake {
+    pub fn new(features: Vec<Feature>) -> Self {
+        Self(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, features)
+    }
+
+    // the newest version both sides speak, None if there is none
+    pub fn agree(&self, other: &Handshake) -> Option<Negotiated> {
+        let version = self.1.min(other.1);
+        if version < self.0.max(other.0) {
+            return None
+        }
+        let features = self.2.iter().filter(|f| other.2.contains(f)).copied().collect();
+        Some(Negotiated(version, features))
+    }
+
+    // shown by both sides when agree found no common version
+    pub fn refusal(client: &Handshake, server: &Handshake) -> Reason {
+        let outdated = if client.1 < server.0 { "please update your client" } else { "the server is too old for this client" };
+        format!("incompatible protocol, the server speaks versions {} to {}, the client {} to {}, {}", server.0, server.1, client.0, client.1, outdated)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerRegisterRequest(pub UserName, pub Password, pub Handshake);
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerRegisterResponse(pub UserId);
+
+// the session token is used to open the websocket and to disconnect again,
+// the name in the response is the one shown to others which differs from the account name after /e
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerConnectRequest(pub UserName);
+pub struct ServerConnectRequest(pub UserName, pub Password, pub Handshake);
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerConnectResponse(pub UserId, pub Version);
+pub struct ServerConnectResponse(pub UserId, pub SessionToken, pub UserName, pub Version, pub Negotiated);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerDisconnectRequest(pub UserId);
+pub struct ServerDisconnectRequest(pub SessionToken);
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerDisconnectResponse();
 
-// no request data
+// no request data, lets clients check compatibility and validate input before registering or connecting
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerVersion(pub Version, pub Handshake, pub Rules);
+
+// POST api/files/<token>/<chat id>?name=<file name> with the raw file as body
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerUploadResponse(pub FileId);
+// GET api/files/<token>/<chat id>/<file id> returns the raw file, the name is sent as content-disposition
+
+// size in bytes
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerVersion(pub Version);
+pub struct FileInfo(pub FileId, pub FileName, pub u64, pub UserName);
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage(pub MessageId, pub UserId, pub UserName, pub String);
+
+// ban and mute without duration last until pardoned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ModAction {
+    Kick,
+    Ban(Option<Seconds>),
+    Mute(Option<Seconds>),
+    Pardon
+}
+
+// setting someone as owner transfers the ownership, the previous owner stays admin
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    Owner,
+    Admin,
+    Member
+}
+
+// public chats are listed in the directory and can be joined without an invite
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Visibility {
+    Public,
+    #[default]
+    InviteOnly
+}
+
+// id, title, member count and topic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PublicChat(pub ChatId, pub ChatTitle, pub usize, pub Option<String>);
+
+// invite id, creator name, uses left and seconds until it expires, None means unlimited
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InviteInfo(pub InviteId, pub UserName, pub Option<u32>, pub Option<Seconds>);
+
+// the server answers every request with a Reply carrying the same id once it was handled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientWsRequest(pub RequestId, pub ClientWsMessage);
+
+// everything that concerns a single chat carries its id, users can be in several chats at once
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientWsMessage{
-    Message(String),
+    Message(ChatId, String),
     ChatCreate(ChatTitle),
-    ChatJoin(ChatId, InviteId),
-    ChatLeave,
-    ChatCreateInvite,
-    ChatListMembers
+    // the chat is found by its title or its id, public chats need no invite
+    ChatJoin(ChatTitle, Option<InviteId>),
+    // lists the public chats
+    ChatDirectory,
+    ChatLeave(ChatId),
+    // most uses and seconds the invite is valid for, None or 0 uses means unlimited
+    ChatCreateInvite(ChatId, Option<u32>, Option<Seconds>),
+    ChatRevokeInvite(ChatId, InviteId),
+    ChatListInvites(ChatId),
+    ChatListMembers(ChatId),
+    // up to n messages before the given one, or the latest ones if None
+    ChatHistory(ChatId, Option<MessageId>, usize),
+    ChatModerate(ChatId, UserName, ModAction),
+    ChatSetRole(ChatId, UserName, Role),
+    ChatListFiles(ChatId),
+    ChatSetVisibility(ChatId, Visibility),
+    // None removes the topic
+    ChatSetTopic(ChatId, Option<String>),
+    // to a user that is online, no matter which chats they are in
+    DirectMessage(UserName, String),
+    // the name shown to others, logging in still uses the account name
+    SetNick(UserName)
 }
+
+impl ClientWsMessage {
+    pub fn chat_id(&self) -> Option<&ChatId> {
+        match self {
+            ClientWsMessage::Message(chat_id, _)
+            | ClientWsMessage::ChatLeave(chat_id)
+            | ClientWsMessage::ChatCreateInvite(chat_id, _, _)
+            | ClientWsMessage::ChatRevokeInvite(chat_id, _)
+            | ClientWsMessage::ChatListInvites(chat_id)
+            | ClientWsMessage::ChatListMembers(chat_id)
+            | ClientWsMessage::ChatHistory(chat_id, _, _)
+            | ClientWsMessage::ChatModerate(chat_id, _, _)
+            | ClientWsMessage::ChatSetRole(chat_id, _, _)
+            | ClientWsMessage::ChatListFiles(chat_id)
+            | ClientWsMessage::ChatSetVisibility(chat_id, _)
+            | ClientWsMessage::ChatSetTopic(chat_id, _) => Some(chat_id),
+            ClientWsMessage::ChatCreate(_)
+            | ClientWsMessage::ChatJoin(_, _)
+            | ClientWsMessage::ChatDirectory
+            | ClientWsMessage::DirectMessage(_, _)
+            | ClientWsMessage::SetNick(_) => None
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerWsMessage{
-    Message(UserId, UserName, String),
+    Message(ChatId, UserId, UserName, String),
     SystemMessage(String),
-    SystemEvent(ServerEvent)
+    // e.g. users joining or leaving
+    ChatSystemMessage(ChatId, String),
+    SystemEvent(ServerEvent),
+    // oldest first
+    History(ChatId, Vec<ChatMessage>),
+    // sender id, sender name, recipient name, sent to both sides
+    DirectMessage(UserId, UserName, UserName, String),
+    // most members first
+    ChatDirectory(Vec<PublicChat>),
+    // the open invites of a chat, only sent to admins
+    Invites(ChatId, Vec<InviteInfo>),
+    // follows whatever the request sent to the requester alone, chat broadcasts may still arrive after it
+    Reply(RequestId, Result<(), ClcError>)
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerEvent{
     ChatCreate(ChatId, ChatTitle),
     ChatAccept(ChatId, ChatTitle),
-    SetAdmin(bool)
-}
\ No newline at end of file
+    // sent for every chat you are in when the websocket opens, with whether you are admin there,
+    // a client that logged in again or the server restarting would not know about them otherwise
+    ChatResumed(ChatId, ChatTitle, bool),
+    // you were removed from the chat, e.g. kicked or the chat was disbanded
+    ChatLeave(ChatId),
+    SetAdmin(ChatId, bool),
+    Moderated(ChatId, UserName, ModAction),
+    RoleChanged(ChatId, UserName, Role),
+    FileShared(ChatId, FileInfo),
+    // user id, old name, new name, sent to everyone sharing a chat with the user
+    NickChanged(UserId, UserName, UserName)
+}
+
+// why a request failed, the names are the ones given in the request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ClcError {
+    InvalidName(Invalid),
+    InvalidTitle(Invalid),
+    InvalidMessage(Invalid),
+    InvalidTopic(Invalid),
+    // someone else already uses the name or one that looks like it
+    NameTaken(UserName),
+    // several users online have that name
+    AmbiguousName(UserName),
+    // another chat already has the title or one that looks like it
+    TitleTaken(ChatTitle),
+    ChatNotFound,
+    NotAMember,
+    AlreadyMember,
+    InvalidInvite,
+    Banned,
+    Muted,
+    NotAdmin,
+    NotOwner,
+    // moderating, changing the role of or messaging yourself
+    TargetIsSelf,
+    UserNotFound(UserName),
+    UserNotOnline(UserName),
+    TargetNotMember(UserName),
+    // only the owner can moderate admins
+    TargetIsAdmin(UserName),
+    TargetNotRestricted(UserName),
+    AlreadyAdmin(UserName),
+    TargetNotAdmin(UserName),
+    // too many requests of that kind, the seconds until it is accepted again
+    SlowDown(Seconds),
+    // a ban, mute or invite lasting that many seconds is too long
+    InvalidDuration(Seconds),
+    // the request could not be parsed
+    InvalidRequest
+}
+
+impl Display for ClcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClcError::InvalidName(invalid) => write!(f, "name {}", invalid),
+            ClcError::InvalidTitle(invalid) => write!(f, "title {}", invalid),
+            ClcError::InvalidMessage(invalid) => write!(f, "message {}", invalid),
+            ClcError::InvalidTopic(invalid) => write!(f, "topic {}", invalid),
+            ClcError::TitleTaken(title) => write!(f, "title {} is already taken", title),
+            ClcError::ChatNotFound => write!(f, "chat does not seem to exist"),
+            ClcError::NotAMember => write!(f, "you are not a member of this chat"),
+            ClcError::AlreadyMember => write!(f, "you already are a member of this chat"),
+            ClcError::InvalidInvite => write!(f, "invite id is invalid"),
+            ClcError::Banned => write!(f, "you are banned from this chat"),
+            ClcError::Muted => write!(f, "you are muted in this chat"),
+            ClcError::NotAdmin => write!(f, "you have to be admin of this chat"),
+            ClcError::NotOwner => write!(f, "you have to be owner of this chat"),
+            ClcError::NameTaken(name) => write!(f, "name {} is already taken", name),
+            ClcError::AmbiguousName(name) => write!(f, "there are several users called {}", name),
+            ClcError::TargetIsSelf => write!(f, "you can't do that to yourself"),
+            ClcError::UserNotFound(name) => write!(f, "user {} does not exist", name),
+            ClcError::UserNotOnline(name) => write!(f, "{} is not online", name),
+            ClcError::TargetNotMember(name) => write!(f, "{} is not a member of this chat", name),
+            ClcError::TargetIsAdmin(name) => write!(f, "only the owner can moderate {}", name),
+            ClcError::TargetNotRestricted(name) => write!(f, "{} is neither banned nor muted", name),
+            ClcError::AlreadyAdmin(name) => write!(f, "{} is already admin", name),
+            ClcError::TargetNotAdmin(name) => write!(f, "{} is not admin", name),
+            ClcError::SlowDown(secs) => write!(f, "slow down, try again in {} seconds", secs),
+            ClcError::InvalidDuration(secs) => write!(f, "{} seconds is too long", secs),
+            ClcError::InvalidRequest => write!(f, "the server did not understand the request")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_agrees_on_newest_common_version() {
+        let client = Handshake(1, 3, vec![Feature::History, Feature::Files]);
+        let server = Handshake(2, 4, vec![Feature::Files, Feature::DirectMessages]);
+        assert_eq!(client.agree(&server), Some(Negotiated(3, vec![Feature::Files])));
+        assert_eq!(server.agree(&client), Some(Negotiated(3, vec![Feature::Files])));
+        assert_eq!(Handshake(1, 1, vec![]).agree(&server), None);
+        assert_eq!(Handshake(5, 6, vec![]).agree(&server), None);
+    }
+}
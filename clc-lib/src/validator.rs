@@ -1,8 +1,228 @@
-pub fn is_valid_name(ident: &str) -> bool{
-    for c in ident.chars() {
-        if !(('0'..'9').contains(&c) || ('a'..'z').contains(&c) || ('A'..'Z').contains(&c) || "_-.~#".contains(c)){
-            return false
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+
+// punctuation allowed in names and titles besides letters and digits
+const NAME_PUNCTUATION: &str = "_-.~#";
+
+// which letters and digits are allowed in names and titles
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodePolicy {
+    // a-z, A-Z and 0-9 only
+    Ascii,
+    // letters and digits of any script, but latin, greek and cyrillic can't be mixed
+    // as their look-alike letters could be used to impersonate someone
+    Unicode,
+    // letters and digits of any script in any combination
+    Permissive
+}
+
+// length and charset rules shared by the server and the client pre-checks, lengths count characters,
+// unknown fields are ignored so older clients still understand newer servers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Rules {
+    pub name_min: usize,
+    pub name_max: usize,
+    pub title_min: usize,
+    pub title_max: usize,
+    pub message_max: usize,
+    pub topic_max: usize,
+    pub password_min: usize,
+    pub password_max: usize,
+    pub unicode: UnicodePolicy,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            name_min: 3,
+            name_max: 16,
+            title_min: 3,
+            title_max: 24,
+            message_max: 2000,
+            topic_max: 120,
+            password_min: 8,
+            password_max: 128,
+            unicode: UnicodePolicy::Ascii,
         }
     }
-    true
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Invalid {
+    // min and max length
+    Length(usize, usize),
+    Characters,
+    MixedScripts
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Invalid::Length(min, max) => write!(f, "should be between {} and {} characters long", min, max),
+            Invalid::Characters => write!(f, "contains characters that are not allowed"),
+            Invalid::MixedScripts => write!(f, "mixes latin, greek or cyrillic letters")
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic
+}
+
+// only the scripts with letters that look alike are told apart
+fn confusable_script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Some(Script::Greek),
+        '\u{0400}'..='\u{052F}' => Some(Script::Cyrillic),
+        _ => None
+    }
+}
+
+// names with the same skeleton can be mistaken for each other: case is ignored and
+// greek and cyrillic letters are replaced by the latin ones they look like
+pub fn skeleton(name: &str) -> String {
+    name.chars().flat_map(char::to_lowercase).map(|c| match c {
+        'а' | 'α' => 'a',
+        'с' => 'c',
+        'ԁ' => 'd',
+        'е' => 'e',
+        'һ' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'κ' => 'k',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        c => c
+    }).collect()
+}
+
+fn check_length(value: &str, min: usize, max: usize) -> Result<(), Invalid> {
+    let len = value.chars().count();
+    if len < min || len > max {
+        return Err(Invalid::Length(min, max))
+    }
+    Ok(())
+}
+
+fn check_free_text(text: &str) -> Result<(), Invalid> {
+    if text.chars().any(char::is_control) {
+        return Err(Invalid::Characters)
+    }
+    Ok(())
+}
+
+impl Rules {
+    fn check_identifier(&self, ident: &str) -> Result<(), Invalid> {
+        let allowed = |c: char| NAME_PUNCTUATION.contains(c) || match self.unicode {
+            UnicodePolicy::Ascii => c.is_ascii_alphanumeric(),
+            UnicodePolicy::Unicode | UnicodePolicy::Permissive => c.is_alphanumeric()
+        };
+        if !ident.chars().all(allowed) {
+            return Err(Invalid::Characters)
+        }
+        if self.unicode == UnicodePolicy::Unicode {
+            let mut scripts = ident.chars().filter_map(confusable_script);
+            if let Some(first) = scripts.next() {
+                if scripts.any(|script| script != first) {
+                    return Err(Invalid::MixedScripts)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_name(&self, name: &str) -> Result<(), Invalid> {
+        check_length(name, self.name_min, self.name_max)?;
+        self.check_identifier(name)
+    }
+
+    pub fn check_title(&self, title: &str) -> Result<(), Invalid> {
+        check_length(title, self.title_min, self.title_max)?;
+        self.check_identifier(title)
+    }
+
+    // messages and topics are free text, only control characters are rejected
+    pub fn check_message(&self, message: &str) -> Result<(), Invalid> {
+        check_length(message, 1, self.message_max)?;
+        check_free_text(message)
+    }
+
+    // splits a message that is too long into parts that are not, at whitespace where possible
+    pub fn split_message(&self, message: &str) -> Vec<String> {
+        let max = self.message_max.max(1);
+        let mut parts = vec![];
+        let mut rest = message.trim();
+        while rest.chars().count() > max {
+            let end = rest.char_indices().nth(max).map(|(i, _)| i).unwrap_or(rest.len());
+            let split = rest[..end].rfind(char::is_whitespace).filter(|i| *i > 0).unwrap_or(end);
+            parts.push(rest[..split].trim_end().to_string());
+            rest = rest[split..].trim_start();
+        }
+        if !rest.is_empty() {
+            parts.push(rest.to_string());
+        }
+        parts
+    }
+
+    pub fn check_topic(&self, topic: &str) -> Result<(), Invalid> {
+        check_length(topic, 1, self.topic_max)?;
+        check_free_text(topic)
+    }
+
+    pub fn check_password(&self, password: &str) -> Result<(), Invalid> {
+        check_length(password, self.password_min, self.password_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_follow_unicode_policy() {
+        let mut rules = Rules::default();
+        assert_eq!(rules.check_name("Zoe_1989"), Ok(()));
+        assert_eq!(rules.check_name("zz"), Err(Invalid::Length(3, 16)));
+        assert_eq!(rules.check_name("bob smith"), Err(Invalid::Characters));
+        assert_eq!(rules.check_name("jürgen"), Err(Invalid::Characters));
+        rules.unicode = UnicodePolicy::Unicode;
+        assert_eq!(rules.check_name("jürgen"), Ok(()));
+        assert_eq!(rules.check_name("Дмитрий"), Ok(()));
+        // cyrillic 'а' in an otherwise latin name
+        assert_eq!(rules.check_name("p\u{0430}ypal"), Err(Invalid::MixedScripts));
+        assert_eq!(rules.check_name("zero\u{200B}width"), Err(Invalid::Characters));
+        rules.unicode = UnicodePolicy::Permissive;
+        assert_eq!(rules.check_name("p\u{0430}ypal"), Ok(()));
+    }
+
+    #[test]
+    fn long_messages_are_split() {
+        let rules = Rules { message_max: 10, ..Default::default() };
+        assert_eq!(rules.split_message(" short "), vec!["short"]);
+        assert_eq!(rules.split_message("hello there world"), vec!["hello", "there", "world"]);
+        assert_eq!(rules.split_message("abcdefghijklmnop"), vec!["abcdefghij", "klmnop"]);
+        assert_eq!(rules.split_message("ääääääääääää"), vec!["ää".repeat(5), "ää".to_string()]);
+        assert!(rules.split_message("   ").is_empty());
+    }
+
+    #[test]
+    fn look_alikes_share_a_skeleton() {
+        assert_eq!(skeleton("PayPal"), skeleton("p\u{0430}yp\u{0430}l"));
+        assert_eq!(skeleton("\u{0441}\u{043E}\u{0440}\u{0435}"), "cope");
+        assert_ne!(skeleton("bob"), skeleton("bob2"));
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use uuid::Uuid;
+use clc_lib::protocol::{ProtocolVersion, SessionToken, UserId};
+use crate::{Clients, Sessions, debug};
+
+// how long a token can be used to open a websocket after logging in
+const SESSION_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub(crate) user_id: UserId,
+    pub(crate) issued: Instant,
+    // set once the token was used to open the websocket
+    pub(crate) connected: bool,
+    // negotiated at login, the websocket is spoken in this version
+    pub(crate) protocol: ProtocolVersion,
+    // when the websocket dropped, the token can be used again until the grace period ends
+    pub(crate) dropped: Option<Instant>,
+}
+
+impl Session {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.issued.elapsed() > SESSION_TTL
+    }
+
+    pub(crate) fn can_connect(&self) -> bool {
+        !self.connected && (self.dropped.is_some() || !self.is_expired())
+    }
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false
+    }
+}
+
+pub(crate) async fn create_session(user_id: &UserId, protocol: ProtocolVersion, sessions: &Sessions) -> SessionToken {
+    let token = format!("{}{}", Uuid::new_v4().as_simple(), Uuid::new_v4().as_simple());
+    let mut sessions_w = sessions.write().await;
+    // tokens that were never used for a websocket are only cleaned up here
+    sessions_w.retain(|_, session| session.connected || session.dropped.is_some() || !session.is_expired());
+    sessions_w.insert(token.clone(), Session { user_id: user_id.clone(), issued: Instant::now(), connected: false, protocol, dropped: None });
+    token
+}
+
+// a token that is never used would keep the client of its login forever
+pub(crate) async fn expire_unused_session(token: SessionToken, clients: Clients, sessions: Sessions) {
+    tokio::time::sleep(SESSION_TTL).await;
+    let mut clients_w = clients.write().await;
+    let mut sessions_w = sessions.write().await;
+    let user_id = match sessions_w.get(&token) {
+        Some(session) if !session.connected && session.dropped.is_none() => session.user_id.clone(),
+        _ => return
+    };
+    sessions_w.remove(&token);
+    // logged in again or still connected with another token
+    if sessions_w.values().any(|session| session.user_id == user_id) {
+        return
+    }
+    if clients_w.get(&user_id).is_some_and(|c| c.sender.is_none()) {
+        clients_w.remove(&user_id);
+        debug!("session of {} expired unused", user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_roundtrip() {
+        let hash = hash_password("correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+}
@@ -1,155 +1,548 @@
-use std::collections::HashSet;
-use std::ops::ControlFlow;
-use std::sync::RwLockWriteGuard;
-use futures::SinkExt;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use futures::future::join_all;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 use warp::ws::Message;
-use clc_lib::protocol::{ChatId, ChatTitle, InviteId, ServerEvent, ServerWsMessage, UserId};
+use clc_lib::protocol::{ChatId, ChatMessage, ClcError, ChatTitle, ClientWsMessage, FileId, FileInfo, FileName, InviteId, MessageId, PublicChat, Role, ServerEvent, ServerWsMessage, UserId, UserName, Visibility};
 use clc_lib::serialize;
-use clc_lib::validator::is_valid_name;
-use crate::{Chat, Chats, Client, Clients, debug};
+use crate::{Chat, Chats, Client, Clients, Config, Store, WsResult, debug, error};
+use crate::ws::Outbox;
+use crate::moderation::{is_restricted, moderate, set_role};
+use crate::files::{remove_chat_files, share_file};
+use crate::config::NameScope;
+use crate::names::taken_in_chat;
+use crate::invites::{create_invite, is_valid, list_invites, redeem, revoke_invite};
 
-pub(crate) async fn create_chat(title: ChatTitle, user_id: &UserId, clients: &Clients, chats: &Chats){
-    if title.len() < 3 || title.len() > 24 {
-        send_msg(clients.read().await.get(user_id).unwrap(),
-                 ServerWsMessage::SystemMessage(format!("title should be between 3 and 24 characters long, found {}", title.len()))).await;
-        return
-    }
+// every chat is run by its own task which owns the chat, everything else talks to it through its handle,
+// so no lock is held while a chat changes. messages only need read access to the clients and are saved by the task
+// in the background, so chats don't wait for each other on them, only rarer requests like joins lock the storage
+pub(crate) enum ChatCommand {
+    // a request of a member concerning this chat
+    Request(UserId, ClientWsMessage, oneshot::Sender<WsResult>),
+    Join(UserId, Option<InviteId>, oneshot::Sender<WsResult>),
+    Members(oneshot::Sender<HashSet<UserId>>),
+    // forward the messages of the chat to the new connection of a member
+    Subscribe(UserId),
+    // only public chats answer with a summary
+    Summary(oneshot::Sender<Option<PublicChat>>),
+    // moves an uploaded file from its temporary path into the chat, failures are answered with a reason
+    ShareFile(UserId, PathBuf, FileName, u64, oneshot::Sender<Result<FileId, String>>),
+    FileName(UserId, FileId, oneshot::Sender<Option<FileName>>),
+    // saves messages not saved yet, used before the server stops
+    Save(oneshot::Sender<()>)
+}
+
+#[derive(Clone)]
+pub(crate) struct ChatHandle {
+    pub(crate) title: ChatTitle,
+    pub(crate) sender: mpsc::UnboundedSender<ChatCommand>
+}
 
-    if !is_valid_name(&title) {
-        send_msg(clients.read().await.get(user_id).unwrap(),
-                 ServerWsMessage::SystemMessage(format!("title is not valid"))).await;
-        return
+impl ChatHandle {
+    // None if the chat was disbanded in the meantime
+    pub(crate) async fn ask<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> ChatCommand) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        self.sender.send(command(reply)).ok()?;
+        response.await.ok()
     }
+}
+
+// what a chat needs to reach its members and to persist itself
+#[derive(Clone)]
+pub(crate) struct ChatContext {
+    pub(crate) clients: Clients,
+    pub(crate) chats: Chats,
+    pub(crate) store: Store,
+    pub(crate) config: Config
+}
+
+// what a chat sends to the connections of its members, serialized once for all of them
+#[derive(Debug, Clone)]
+pub(crate) enum Delivery {
+    All(Message),
+    // ends the subscription of a member that left or was removed, with a last message for them
+    Remove(UserId, Option<Message>)
+}
+
+pub(crate) fn spawn_chat(mut chat: Chat, context: ChatContext) -> ChatHandle {
+    chat.fanout = Some(broadcast::channel(context.config.connection.chat_backlog).0);
+    let (sender, commands) = mpsc::unbounded_channel();
+    let handle = ChatHandle { title: chat.title.clone(), sender };
+    tokio::spawn(run_chat(chat, commands, context));
+    handle
+}
 
-    let mut chats_w = chats.write().await;
-    let mut clients_w = clients.write().await;
-    let mut c = clients_w.get_mut(user_id).unwrap();
+async fn run_chat(mut chat: Chat, mut commands: mpsc::UnboundedReceiver<ChatCommand>, context: ChatContext) {
     {
-        let uuid = Uuid::new_v4().as_simple().to_string();
-        debug!("created chat {} {}", title, uuid);
-        send_msg(c,ServerWsMessage::SystemEvent(ServerEvent::ChatCreate(uuid.clone(), title.clone()))).await;
-        c.chat = Some(uuid.clone());
-        chats_w.insert(
-            uuid.clone(),
-            Chat {
-                chat_id: uuid.clone(),
-                title,
-                owner: user_id.clone(),
-                users: HashSet::from([c.user_id.clone()]),
-                invites: Default::default()
+        let clients_r = context.clients.read().await;
+        for user_id in chat.users.iter() {
+            subscribe(&chat, user_id, &clients_r);
+        }
+    }
+    let mut saves = tokio::time::interval(Duration::from_secs(context.config.flush_interval.max(1)));
+    loop {
+        let command = tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => break
             },
-        );
+            _ = saves.tick() => {
+                save_messages(&mut chat, &context).await;
+                continue;
+            }
+        };
+        let mut disband = false;
+        match command {
+            ChatCommand::Request(user_id, ClientWsMessage::ChatLeave(_), reply) => {
+                let result = match chat.users.contains(&user_id) {
+                    true => Ok(leave_chat(&mut chat, &user_id, &context).await),
+                    false => Err(ClcError::NotAMember)
+                };
+                disband = result == Ok(true);
+                let _ = reply.send(result.map(|_| ()));
+            }
+            ChatCommand::Request(user_id, message, reply) => {
+                let result = match chat.users.contains(&user_id) {
+                    true => handle_request(&mut chat, &user_id, message, &context).await,
+                    false => Err(ClcError::NotAMember)
+                };
+                let _ = reply.send(result);
+            }
+            ChatCommand::Join(user_id, invite, reply) => {
+                let _ = reply.send(join_chat(&mut chat, &user_id, invite, &context).await);
+            }
+            ChatCommand::Members(reply) => {
+                let _ = reply.send(chat.users.clone());
+            }
+            ChatCommand::Subscribe(user_id) => if chat.users.contains(&user_id) {
+                let clients_r = context.clients.read().await;
+                // the member learns about the chat before its messages are forwarded
+                if let Some(client) = clients_r.get(&user_id) {
+                    send_msg(client, ServerWsMessage::SystemEvent(ServerEvent::ChatResumed(chat.chat_id.clone(), chat.title.clone(), chat.is_admin(&user_id)))).await;
+                }
+                subscribe(&chat, &user_id, &clients_r);
+            }
+            ChatCommand::Summary(reply) => {
+                let summary = (chat.visibility == Visibility::Public)
+                    .then(|| PublicChat(chat.chat_id.clone(), chat.title.clone(), chat.users.len(), chat.topic.clone()));
+                let _ = reply.send(summary);
+            }
+            ChatCommand::ShareFile(user_id, tmp, name, size, reply) => {
+                let _ = reply.send(share_file(&mut chat, &user_id, tmp, name, size, &context).await);
+            }
+            ChatCommand::FileName(user_id, file_id, reply) => {
+                let name = chat.files.iter()
+                    .find(|f| f.0 == file_id && chat.users.contains(&user_id))
+                    .map(|f| f.1.clone());
+                let _ = reply.send(name);
+            }
+            ChatCommand::Save(reply) => {
+                save_messages(&mut chat, &context).await;
+                let _ = reply.send(());
+            }
+        }
+        if disband {
+            disband_chat(&chat, &context).await;
+            // commands still queued are dropped with the receiver, their senders get no answer,
+            // subscribers get what is left in the backlog before their subscription ends with the chat
+            break;
+        }
+    }
+}
+
+// membership was checked already
+async fn handle_request(chat: &mut Chat, user_id: &UserId, message: ClientWsMessage, context: &ChatContext) -> WsResult {
+    match message {
+        ClientWsMessage::Message(_, content) => send_chat_message(chat, user_id, content, context).await,
+        ClientWsMessage::ChatCreateInvite(_, uses, valid_for) => create_invite(chat, user_id, uses, valid_for, context).await,
+        ClientWsMessage::ChatRevokeInvite(_, invite_id) => revoke_invite(chat, user_id, invite_id, context).await,
+        ClientWsMessage::ChatListInvites(_) => list_invites(chat, user_id, context).await,
+        ClientWsMessage::ChatListMembers(_) => list_members(chat, user_id, context).await,
+        ClientWsMessage::ChatListFiles(_) => list_files(chat, user_id, context).await,
+        ClientWsMessage::ChatHistory(_, before, count) => send_history(chat, user_id, before, count, context).await,
+        ClientWsMessage::ChatModerate(_, target, action) => moderate(chat, user_id, target, action, context).await,
+        ClientWsMessage::ChatSetRole(_, target, role) => set_role(chat, user_id, target, role, context).await,
+        ClientWsMessage::ChatSetVisibility(_, visibility) => set_visibility(chat, user_id, visibility, context).await,
+        ClientWsMessage::ChatSetTopic(_, topic) => set_topic(chat, user_id, topic, context).await,
+        // only requests with a chat id are sent to chats and leaving is handled by run_chat, but a bad request must not end the chat
+        ClientWsMessage::ChatLeave(_)
+        | ClientWsMessage::ChatCreate(_)
+        | ClientWsMessage::ChatJoin(_, _)
+        | ClientWsMessage::ChatDirectory
+        | ClientWsMessage::DirectMessage(_, _)
+        | ClientWsMessage::SetNick(_) => Err(ClcError::InvalidRequest)
+    }
+}
+
+// sends a request carrying a chat id to that chat
+pub(crate) async fn chat_request(user_id: &UserId, message: ClientWsMessage, chats: &Chats) -> WsResult {
+    let handle = match message.chat_id() {
+        Some(chat_id) => chats.read().await.get(chat_id).cloned(),
+        None => None
     };
+    match handle {
+        Some(handle) => handle.ask(|reply| ChatCommand::Request(user_id.clone(), message, reply)).await.unwrap_or(Err(ClcError::NotAMember)),
+        None => Err(ClcError::NotAMember)
+    }
 }
 
-pub(crate) async fn create_chat_invite(user_id: &UserId, clients: &Clients, chats: &Chats){
-    let mut chats_w = chats.write().await;
-    let clients_w = clients.write().await;
-    let user = clients_w.get(user_id).unwrap();
-    let chat = chats_w.get_mut(user.chat.as_ref().unwrap()).unwrap();
-    if user.user_id != chat.owner {
-        send_msg(user, ServerWsMessage::SystemMessage(format!("You have to be admin to create an invite"))).await;
-        return;
-    }
-    let invite = Uuid::new_v4().as_simple().to_string();
-    send_msg(user, ServerWsMessage::SystemMessage(format!("Created invite: {}", invite))).await;
-    chat.invites.insert(invite);
-}
-
-pub(crate) async fn join_chat(user_id: &UserId, chat_title: ChatTitle, invite: InviteId, clients: &Clients, chats: &Chats){
-    let mut joined_chat = None;
-    let mut chats_w = chats.write().await;
-    let chat_exists = chats_w.iter_mut().map(|(id, chat)| {
-        if chat.title == chat_title {
-            if chat.invites.contains(&invite) {
-                chat.invites.remove(&invite);
-                chat.users.insert(user_id.to_string());
-                joined_chat = Some(chat);
-            }
-            true
-        } else {
-            false
+pub(crate) async fn create_chat(title: ChatTitle, user_id: &UserId, clients: &Clients, chats: &Chats, store: &Store, config: &Config) -> WsResult {
+    config.limits.check_title(&title).map_err(ClcError::InvalidTitle)?;
+
+    let chat_id = Uuid::new_v4().as_simple().to_string();
+    let chat = Chat {
+        chat_id: chat_id.clone(),
+        title: title.clone(),
+        owner: user_id.clone(),
+        admins: Default::default(),
+        users: HashSet::from([user_id.clone()]),
+        invites: Default::default(),
+        history: Default::default(),
+        banned: Default::default(),
+        muted: Default::default(),
+        files: Default::default(),
+        visibility: Default::default(),
+        topic: None,
+        fanout: None,
+        message_rate: Default::default(),
+        unsaved: false
+    };
+    {
+        let mut chats_w = chats.write().await;
+        if chats_w.title_taken(&title) {
+            return Err(ClcError::TitleTaken(title))
         }
-    }).any(|x| x);// we use .map().any() instead of just .any() because any short-circuits
-
-    match joined_chat {
-        None => {
-            let clients_w = clients.write().await;
-            let user = clients_w.get(user_id).unwrap();
-            if chat_exists {
-                send_msg(user, ServerWsMessage::SystemMessage(format!("Your invite id is invalid"))).await;
-            } else {
-                send_msg(user, ServerWsMessage::SystemMessage(format!("Chat {} does not seem to exist", chat_title))).await;
-            }
+        store.lock().await.save_chat(&chat);
+        let context = ChatContext { clients: clients.clone(), chats: chats.clone(), store: store.clone(), config: config.clone() };
+        chats_w.insert(chat_id.clone(), spawn_chat(chat, context));
+    }
+    debug!("created chat {} {}", title, chat_id);
+    send_to(user_id, ServerWsMessage::SystemEvent(ServerEvent::ChatCreate(chat_id, title)), clients).await;
+    Ok(())
+}
+
+// the chat is found by its title or its id
+pub(crate) async fn request_join(user_id: &UserId, title_or_id: ChatTitle, invite: Option<InviteId>, chats: &Chats) -> WsResult {
+    let handle = {
+        let chats_r = chats.read().await;
+        chats_r.find(&title_or_id).and_then(|chat_id| chats_r.get(&chat_id).cloned())
+    };
+    match handle {
+        Some(handle) => handle.ask(|reply| ChatCommand::Join(user_id.clone(), invite, reply)).await.unwrap_or(Err(ClcError::ChatNotFound)),
+        None => Err(ClcError::ChatNotFound)
+    }
+}
+
+async fn join_chat(chat: &mut Chat, user_id: &UserId, invite: Option<InviteId>, context: &ChatContext) -> WsResult {
+    if chat.users.contains(user_id) {
+        return Err(ClcError::AlreadyMember)
+    }
+    if is_restricted(&mut chat.banned, user_id) {
+        return Err(ClcError::Banned)
+    }
+    // invites to public chats are kept for when the chat becomes invite only again
+    let invite = match chat.visibility {
+        Visibility::Public => None,
+        Visibility::InviteOnly => match invite.filter(|invite| is_valid(&mut chat.invites, invite)) {
+            Some(invite) => Some(invite),
+            None => return Err(ClcError::InvalidInvite)
         }
-        Some(chat) => {
-            let user_name = {
-                let clients_w = clients.write().await;
-                let user = clients_w.get(user_id).unwrap();
-                user.user_name.clone()
-            };
-            broadcast_msg(ServerWsMessage::SystemMessage(format!("{} joined chat", user_name)), chat, clients).await;
-            // write to clients AFTER broadcast/inside scope
-            let mut clients_w = clients.write().await;
-            let user = clients_w.get_mut(user_id).unwrap();
-            user.chat = Some(chat.chat_id.clone());
-            send_msg(user, ServerWsMessage::SystemEvent(ServerEvent::ChatAccept(chat.chat_id.clone(), chat_title))).await;
+    };
+    let name = user_name(user_id, &context.clients, &context.store).await;
+    if context.config.names.unique == NameScope::Chat {
+        let clients_r = context.clients.read().await;
+        if taken_in_chat(user_id, &name, &chat.users, &clients_r, context.store.lock().await.as_ref()) {
+            return Err(ClcError::NameTaken(name))
         }
     }
+    if let Some(invite) = invite {
+        redeem(&mut chat.invites, &invite);
+    }
+    chat.users.insert(user_id.to_string());
+    context.store.lock().await.save_chat(chat);
+
+    // the new member learns about the chat before its messages are forwarded
+    send_to(user_id, ServerWsMessage::SystemEvent(ServerEvent::ChatAccept(chat.chat_id.clone(), chat.title.clone())), &context.clients).await;
+    if let Some(topic) = &chat.topic {
+        send_to(user_id, ServerWsMessage::ChatSystemMessage(chat.chat_id.clone(), format!("Topic: {}", topic)), &context.clients).await;
+    }
+    let history = history_page(chat, None, context.config.history.scrollback, &context.config);
+    if !history.is_empty() {
+        send_to(user_id, ServerWsMessage::History(chat.chat_id.clone(), history), &context.clients).await;
+    }
+    subscribe(chat, user_id, &*context.clients.read().await);
+    broadcast_msg(ServerWsMessage::ChatSystemMessage(chat.chat_id.clone(), format!("{} joined chat", name)), chat);
+    Ok(())
 }
 
-pub(crate) async fn leave_chat(user_id: &UserId, clients: &Clients, chats: &Chats){
-    let chat_id = {
-        let cid = clients.write().await.get(user_id).unwrap().chat.clone();
-        if cid == None {
-            return;
+pub(crate) async fn list_public_chats(user_id: &UserId, clients: &Clients, chats: &Chats) -> WsResult {
+    let handles: Vec<ChatHandle> = chats.read().await.values().cloned().collect();
+    let mut directory: Vec<PublicChat> = join_all(handles.iter().map(|handle| handle.ask(ChatCommand::Summary))).await
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+    directory.sort_by(|PublicChat(_, a_title, a_members, _), PublicChat(_, b_title, b_members, _)| b_members.cmp(a_members).then_with(|| a_title.cmp(b_title)));
+    send_to(user_id, ServerWsMessage::ChatDirectory(directory), clients).await;
+    Ok(())
+}
+
+// the members of every chat the user is in
+pub(crate) async fn joined_chats(user_id: &UserId, chats: &Chats) -> Vec<HashSet<UserId>> {
+    let handles: Vec<ChatHandle> = chats.read().await.values().cloned().collect();
+    join_all(handles.iter().map(|handle| handle.ask(ChatCommand::Members))).await
+        .into_iter()
+        .flatten()
+        .filter(|members| members.contains(user_id))
+        .collect()
+}
+
+// a connecting user gets the messages of the chats they are in forwarded again
+pub(crate) async fn subscribe_all(user_id: &UserId, chats: &Chats) {
+    for handle in chats.read().await.values() {
+        let _ = handle.sender.send(ChatCommand::Subscribe(user_id.clone()));
+    }
+}
+
+async fn set_visibility(chat: &mut Chat, user_id: &UserId, visibility: Visibility, context: &ChatContext) -> WsResult {
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    chat.visibility = visibility;
+    context.store.lock().await.save_chat(chat);
+    let name = user_name(user_id, &context.clients, &context.store).await;
+    let line = match visibility {
+        Visibility::Public => format!("{} made the chat public", name),
+        Visibility::InviteOnly => format!("{} made the chat invite only", name)
+    };
+    broadcast_msg(ServerWsMessage::ChatSystemMessage(chat.chat_id.clone(), line), chat);
+    Ok(())
+}
+
+async fn set_topic(chat: &mut Chat, user_id: &UserId, topic: Option<String>, context: &ChatContext) -> WsResult {
+    if let Some(topic) = &topic {
+        context.config.limits.check_topic(topic).map_err(ClcError::InvalidTopic)?;
+    }
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    chat.topic = topic;
+    context.store.lock().await.save_chat(chat);
+    let name = user_name(user_id, &context.clients, &context.store).await;
+    let line = match &chat.topic {
+        Some(topic) => format!("{} set the topic: {}", name, topic),
+        None => format!("{} removed the topic", name)
+    };
+    broadcast_msg(ServerWsMessage::ChatSystemMessage(chat.chat_id.clone(), line), chat);
+    Ok(())
+}
+
+// used when a user disconnects
+pub(crate) async fn leave_all_chats(user_id: &UserId, chats: &Chats) {
+    let handles: Vec<(ChatId, ChatHandle)> = chats.read().await.iter().map(|(chat_id, handle)| (chat_id.clone(), handle.clone())).collect();
+    // chats the user is not in answer with NotAMember, which is of no interest here
+    join_all(handles.iter().map(|(chat_id, handle)| {
+        handle.ask(|reply| ChatCommand::Request(user_id.clone(), ClientWsMessage::ChatLeave(chat_id.clone()), reply))
+    })).await;
+}
+
+// returns whether the chat has to be disbanded
+async fn leave_chat(chat: &mut Chat, user_id: &UserId, context: &ChatContext) -> bool {
+    let chat_id = chat.chat_id.clone();
+    debug!("{} left chat {}", user_id, chat_id);
+    let name = user_name(user_id, &context.clients, &context.store).await;
+    broadcast_msg(ServerWsMessage::ChatSystemMessage(chat_id.clone(), format!("{} left chat", name)), chat);
+    unsubscribe(chat, user_id, None);
+    chat.users.remove(user_id);
+    chat.admins.remove(user_id);
+    if user_id != &chat.owner {
+        context.store.lock().await.save_chat(chat);
+        return false
+    }
+    if context.config.features.promote_on_owner_leave {
+        // prefer an admin, otherwise any member
+        let successor = chat.admins.iter().next().or_else(|| chat.users.iter().next()).cloned();
+        if let Some(successor) = successor {
+            debug!("{} is the new owner of chat {}", successor, chat_id);
+            chat.admins.remove(&successor);
+            chat.owner = successor.clone();
+            context.store.lock().await.save_chat(chat);
+            let successor_name = user_name(&successor, &context.clients, &context.store).await;
+            broadcast_msg(ServerWsMessage::SystemEvent(ServerEvent::RoleChanged(chat_id.clone(), successor_name, Role::Owner)), chat);
+            send_to(&successor, ServerWsMessage::SystemEvent(ServerEvent::SetAdmin(chat_id, true)), &context.clients).await;
+            return false
         }
-        cid.unwrap()
+    }
+    broadcast_msg(ServerWsMessage::ChatSystemMessage(chat_id, format!("{} disbanded chat", name)), chat);
+    true
+}
+
+async fn disband_chat(chat: &Chat, context: &ChatContext) {
+    debug!("disbanded chat {}", chat.chat_id);
+    broadcast_msg(ServerWsMessage::SystemEvent(ServerEvent::ChatLeave(chat.chat_id.clone())), chat);
+    context.chats.write().await.remove(&chat.chat_id);
+    context.store.lock().await.remove_chat(&chat.chat_id);
+    remove_chat_files(&chat.chat_id, &context.config).await;
+}
+
+async fn send_chat_message(chat: &mut Chat, user_id: &UserId, content: String, context: &ChatContext) -> WsResult {
+    context.config.limits.check_message(&content).map_err(ClcError::InvalidMessage)?;
+    if is_restricted(&mut chat.muted, user_id) {
+        return Err(ClcError::Muted)
+    }
+    chat.message_rate.take(&context.config.rates.chat_messages)?;
+    let name = user_name(user_id, &context.clients, &context.store).await;
+    if context.config.features.history {
+        let message_id = chat.history.back().map(|m| m.0 + 1).unwrap_or(0);
+        chat.history.push_back(ChatMessage(message_id, user_id.clone(), name.clone(), content.clone()));
+        while chat.history.len() > context.config.history.limit {
+            chat.history.pop_front();
+        }
+        chat.unsaved = true;
+    }
+    broadcast_msg(ServerWsMessage::Message(chat.chat_id.clone(), user_id.clone(), name, content), chat);
+    Ok(())
+}
+
+async fn save_messages(chat: &mut Chat, context: &ChatContext) {
+    if chat.unsaved {
+        chat.unsaved = false;
+        context.store.lock().await.save_chat(chat);
+    }
+}
+
+// used before the server stops
+pub(crate) async fn save_all_chats(chats: &Chats) {
+    let handles: Vec<ChatHandle> = chats.read().await.values().cloned().collect();
+    join_all(handles.iter().map(|handle| handle.ask(ChatCommand::Save))).await;
+}
+
+async fn send_history(chat: &Chat, user_id: &UserId, before: Option<MessageId>, count: usize, context: &ChatContext) -> WsResult {
+    let page = history_page(chat, before, count, &context.config);
+    send_to(user_id, ServerWsMessage::History(chat.chat_id.clone(), page), &context.clients).await;
+    Ok(())
+}
+
+fn history_page(chat: &Chat, before: Option<MessageId>, count: usize, config: &Config) -> Vec<ChatMessage> {
+    let end = match before {
+        Some(message_id) => chat.history.partition_point(|m| m.0 < message_id),
+        None => chat.history.len()
     };
-    let mut chats_w = chats.write().await;
-    if {
-        let chat = chats_w.get_mut(&chat_id).unwrap();
-        debug!("{} left chat {}", user_id, chat_id);
-        let name = {
-            let mut c = clients.write().await;
-            let mut user = c.get_mut(user_id).unwrap();
-            user.chat = None;
-            user.user_name.clone()
+    let start = end.saturating_sub(count.min(config.history.page_limit));
+    chat.history.range(start..end).cloned().collect()
+}
+
+async fn list_members(chat: &Chat, user_id: &UserId, context: &ChatContext) -> WsResult {
+    let mut response = String::new();
+    response.push_str(&format!("members of {}:\n", chat.title));
+    for user in chat.users.iter() {
+        let role = if user == &chat.owner {
+            " (owner)"
+        } else if chat.admins.contains(user) {
+            " (admin)"
+        } else {
+            ""
         };
-        broadcast_msg(ServerWsMessage::SystemMessage(format!("{} left chat", name)), chat, clients).await;
-        if user_id == &chat.owner {
-            debug!("disbanded chat {}", chat_id);
-            broadcast_msg(ServerWsMessage::SystemMessage(format!("{} disbanded chat", name)), chat, clients).await;
-            for user in chat.users.iter() {
-                if user_id == &chat.owner {
-                    continue
-                }
-                for u_name in clients.write().await.get_mut(user).map(|c| {
-                    c.chat = None;
-                    debug!("{} left chat {}", user, chat_id);
-                    &c.user_name
-                }) {
-                    broadcast_msg(ServerWsMessage::SystemMessage(format!("{} left chat", u_name)), chat, clients).await;
+        let online = context.clients.read().await.get(user).is_some_and(|c| c.sender.is_some());
+        let name = user_name(user, &context.clients, &context.store).await;
+        response.push_str(&format!("    {}{}{}\n", name, role, if online { "" } else { " (offline)" }));
+    }
+    send_to(user_id, ServerWsMessage::SystemMessage(response), &context.clients).await;
+    Ok(())
+}
+
+async fn list_files(chat: &Chat, user_id: &UserId, context: &ChatContext) -> WsResult {
+    let mut response = String::new();
+    response.push_str(&format!("files in {}:\n", chat.title));
+    for FileInfo(file_id, name, size, uploader) in chat.files.iter() {
+        response.push_str(&format!("    {} {} ({} bytes, from {})\n", file_id, name, size, uploader));
+    }
+    send_to(user_id, ServerWsMessage::SystemMessage(response), &context.clients).await;
+    Ok(())
+}
+
+// offline members are only known to the storage
+pub(crate) async fn user_name(user_id: &UserId, clients: &Clients, store: &Store) -> UserName {
+    if let Some(c) = clients.read().await.get(user_id) {
+        return c.user_name.clone();
+    }
+    store.lock().await.account(user_id).map(|a| a.display_name().clone()).unwrap_or_else(|| user_id.clone())
+}
+
+// members that are offline have nothing to forward to, they subscribe again when they connect
+fn subscribe(chat: &Chat, user_id: &UserId, clients: &HashMap<UserId, Client>) {
+    if let (Some(fanout), Some(outbox)) = (&chat.fanout, clients.get(user_id).and_then(|c| c.sender.clone())) {
+        tokio::spawn(forward(chat.chat_id.clone(), user_id.clone(), fanout.subscribe(), outbox));
+    }
+}
+
+// the last message is delivered after everything broadcast before
+pub(crate) fn unsubscribe(chat: &Chat, user_id: &UserId, last: Option<ServerWsMessage>) {
+    if let Some(fanout) = &chat.fanout {
+        let _ = fanout.send(Delivery::Remove(user_id.clone(), last.as_ref().and_then(encode)));
+    }
+}
+
+// forwards what a chat broadcasts to the connection of a member until they leave or the connection closes,
+// a member falling behind the chat backlog misses messages and is told how many
+async fn forward(chat_id: ChatId, user_id: UserId, mut fanout: broadcast::Receiver<Delivery>, outbox: Outbox) {
+    loop {
+        let delivery = tokio::select! {
+            delivery = fanout.recv() => delivery,
+            _ = outbox.closed() => return
+        };
+        let message = match delivery {
+            Ok(Delivery::All(message)) => Some(message),
+            Ok(Delivery::Remove(member, last)) if member == user_id => {
+                if let Some(message) = last {
+                    outbox.send(message).await;
                 }
+                return
+            }
+            Ok(Delivery::Remove(_, _)) => None,
+            Err(RecvError::Lagged(missed)) => {
+                debug!("{} missed {} messages of {}", user_id, missed, chat_id);
+                encode(&ServerWsMessage::ChatSystemMessage(chat_id.clone(), format!("missed {} messages, the connection is too slow", missed)))
+            }
+            Err(RecvError::Closed) => return
+        };
+        if let Some(message) = message {
+            if !outbox.send(message).await {
+                return
             }
         }
-        chat.users.remove(user_id);
-        user_id == &chat.owner
-    } {
-        chats_w.remove(&chat_id);
     }
 }
 
-pub(crate) async fn broadcast_msg(message: ServerWsMessage, chat: &Chat, clients: &Clients){
-    let c = clients.read().await;
-    for user in chat.users.iter() {
-        send_msg(c.get(user).unwrap(), message.clone()).await;
+pub(crate) fn broadcast_msg(message: ServerWsMessage, chat: &Chat) {
+    if let (Some(fanout), Some(message)) = (&chat.fanout, encode(&message)) {
+        // fails only if no member is online
+        let _ = fanout.send(Delivery::All(message));
+    }
+}
+
+// users that logged out in the meantime are skipped
+pub(crate) async fn send_to(user_id: &UserId, message: ServerWsMessage, clients: &Clients) {
+    if let Some(client) = clients.read().await.get(user_id) {
+        send_msg(client, message).await;
     }
 }
 
 pub(crate) async fn send_msg(client: &Client, message: ServerWsMessage) {
-    if let Some(sender) = &client.sender {
-        let _ = sender.send(Ok(Message::text(serialize(&message).unwrap())));
+    if let (Some(outbox), Some(message)) = (&client.sender, encode(&message)) {
+        outbox.push(message);
     }
-}
\ No newline at end of file
+}
+
+fn encode(message: &ServerWsMessage) -> Option<Message> {
+    match serialize(message) {
+        Ok(text) => Some(Message::text(text)),
+        Err(e) => {
+            error!("unable to serialize {:?}: {}", message, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use clc_lib::validator::Rules;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "command line chat server")]
+pub(crate) struct Args {
+    /// address to listen on
+    #[arg(short, long, default_value = "0.0.0.0")]
+    pub(crate) bind: IpAddr,
+    #[arg(short, long, default_value_t = 10000)]
+    pub(crate) port: u16,
+    /// certificate for serving https/wss, requires --tls-key
+    #[arg(long, env = "CLC_TLS_CERT", requires = "tls_key")]
+    pub(crate) tls_cert: Option<PathBuf>,
+    /// private key for serving https/wss, requires --tls-cert
+    #[arg(long, env = "CLC_TLS_KEY", requires = "tls_cert")]
+    pub(crate) tls_key: Option<PathBuf>,
+    /// defaults to debug in debug builds and info in release builds
+    #[arg(short, long, value_enum)]
+    pub(crate) log_level: Option<LogLevel>,
+    /// limits, motd and feature toggles, missing files fall back to the defaults
+    #[arg(short, long, default_value = "clc-server.toml")]
+    pub(crate) config: PathBuf,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LogLevel {
+    Error = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(if cfg!(debug_assertions) { LogLevel::Debug as u8 } else { LogLevel::Info as u8 });
+
+pub(crate) fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    // sent to every user after connecting
+    pub(crate) motd: Option<String>,
+    // path of the json storage file, or "memory" to keep nothing across restarts
+    pub(crate) storage: String,
+    // seconds between writes of the storage file, changes since the last write are lost if the server is killed
+    pub(crate) flush_interval: u64,
+    #[serde(deserialize_with = "known_rules")]
+    pub(crate) limits: Rules,
+    pub(crate) history: History,
+    pub(crate) files: Files,
+    pub(crate) connection: Connection,
+    pub(crate) names: Names,
+    pub(crate) rates: Rates,
+    pub(crate) features: Features,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct History {
+    // messages kept per chat, older ones are dropped
+    pub(crate) limit: usize,
+    // most messages sent for a single history request
+    pub(crate) page_limit: usize,
+    // messages sent right after joining a chat
+    pub(crate) scrollback: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Files {
+    // directory uploaded files are kept in, one subdirectory per chat
+    pub(crate) path: PathBuf,
+    // in bytes
+    pub(crate) max_size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Connection {
+    // seconds a user keeps their chats after the websocket dropped, reconnecting within it resumes the session
+    pub(crate) grace_period: u64,
+    // messages waiting to be sent to a client, a client falling that far behind is disconnected
+    pub(crate) queue_size: usize,
+    // messages a chat keeps for members that are slow to receive them, those falling further behind miss messages
+    pub(crate) chat_backlog: usize,
+    // in bytes, larger websocket frames close the connection
+    pub(crate) max_frame_size: usize,
+    // in bytes, for the json bodies of register, login and logout
+    pub(crate) max_body_size: u64,
+    // seconds between pings to every client, 0 turns them off
+    pub(crate) ping_interval: u64,
+    // pings a client can leave unanswered before it is disconnected
+    pub(crate) missed_pongs: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NameScope {
+    Server,
+    Chat,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Names {
+    // where shown names have to be unique, names that only differ in case or look-alike letters count as equal
+    pub(crate) unique: NameScope,
+}
+
+// up to burst requests at once, refilling at per_minute, a burst of 0 turns the limit off
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rate {
+    pub(crate) burst: u32,
+    pub(crate) per_minute: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Rates {
+    // chat and direct messages of a user
+    pub(crate) messages: Rate,
+    // messages of all members of a chat together
+    pub(crate) chat_messages: Rate,
+    pub(crate) chat_creation: Rate,
+    pub(crate) invites: Rate,
+    // accounts created from the same address
+    pub(crate) registrations: Rate,
+    // login attempts from the same address, successful or not
+    pub(crate) logins: Rate,
+    // users slowed down this often within a minute are muted, 0 never mutes
+    pub(crate) strikes: usize,
+    // seconds
+    pub(crate) mute: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Features {
+    // allow creating new accounts via /api/register
+    pub(crate) registration: bool,
+    // keep chat messages for scrollback
+    pub(crate) history: bool,
+    // when the owner leaves, make an admin (or any member) the new owner instead of disbanding the chat
+    pub(crate) promote_on_owner_leave: bool,
+    // allow sharing files in chats
+    pub(crate) files: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            motd: None,
+            storage: String::from("clc-storage.json"),
+            flush_interval: 5,
+            limits: Default::default(),
+            history: Default::default(),
+            files: Default::default(),
+            connection: Default::default(),
+            names: Default::default(),
+            rates: Default::default(),
+            features: Default::default(),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            limit: 1000,
+            page_limit: 100,
+            scrollback: 20,
+        }
+    }
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("clc-files"),
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            grace_period: 60,
+            queue_size: 256,
+            chat_backlog: 1024,
+            max_frame_size: 64 * 1024,
+            max_body_size: 4 * 1024,
+            ping_interval: 30,
+            missed_pongs: 2,
+        }
+    }
+}
+
+impl Default for Names {
+    fn default() -> Self {
+        Self {
+            unique: NameScope::Server,
+        }
+    }
+}
+
+impl Default for Rates {
+    fn default() -> Self {
+        Self {
+            messages: Rate { burst: 10, per_minute: 60 },
+            chat_messages: Rate { burst: 50, per_minute: 600 },
+            chat_creation: Rate { burst: 3, per_minute: 3 },
+            invites: Rate { burst: 5, per_minute: 10 },
+            registrations: Rate { burst: 3, per_minute: 1 },
+            logins: Rate { burst: 5, per_minute: 5 },
+            strikes: 5,
+            mute: 300,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            registration: true,
+            history: true,
+            promote_on_owner_leave: false,
+            files: true,
+        }
+    }
+}
+
+// the rules accept unknown fields for the sake of clients, a typo in the config should still be an error
+fn known_rules<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rules, D::Error> {
+    let table = toml::Table::deserialize(deserializer)?;
+    let known = toml::Table::try_from(Rules::default()).map_err(D::Error::custom)?;
+    if let Some(key) = table.keys().find(|key| !known.contains_key(*key)) {
+        return Err(D::Error::custom(format!("unknown field `{}` in limits", key)))
+    }
+    toml::Value::Table(table).try_into().map_err(D::Error::custom)
+}
+
+impl ServerConfig {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let config: Self = toml::from_str(&content).map_err(|e| format!("unable to parse {}: {}", path.display(), e))?;
+        // a character takes up to 4 bytes, the rest of the request is small
+        let largest_request = config.limits.message_max * 4 + 1024;
+        if config.connection.max_frame_size < largest_request {
+            return Err(format!("max_frame_size has to be at least {} bytes to fit messages of {} characters", largest_request, config.limits.message_max))
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config: ServerConfig = toml::from_str("motd = \"hi\"\n[limits]\nname_max = 20\n").unwrap();
+        assert_eq!(config.motd.as_deref(), Some("hi"));
+        assert_eq!(config.limits.name_max, 20);
+        assert_eq!(config.limits.name_min, 3);
+        assert_eq!(config.history.scrollback, 20);
+        assert!(config.features.registration);
+        assert!(toml::from_str::<ServerConfig>("[limits]\nname_mx = 20\n").is_err());
+    }
+}
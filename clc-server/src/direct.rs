@@ -0,0 +1,29 @@
+use clc_lib::protocol::{ClcError, ServerWsMessage, UserId, UserName};
+use crate::{Clients, Config, WsResult, debug};
+use crate::chat::send_msg;
+
+// direct messages are only delivered to users that are online and not stored
+pub(crate) async fn send_direct_message(user_id: &UserId, target: UserName, content: String, clients: &Clients, config: &Config) -> WsResult {
+    config.limits.check_message(&content).map_err(ClcError::InvalidMessage)?;
+    let clients_r = clients.read().await;
+    // logged out in the meantime, there is no one to reply to
+    let Some(sender) = clients_r.get(user_id) else {
+        return Ok(())
+    };
+    // names might only be unique per chat
+    let mut recipients = clients_r.values().filter(|c| c.user_name == target && c.sender.is_some());
+    let recipient = match (recipients.next(), recipients.next()) {
+        (Some(recipient), None) => recipient,
+        (Some(_), Some(_)) => return Err(ClcError::AmbiguousName(target)),
+        (None, _) => return Err(ClcError::UserNotOnline(target))
+    };
+    if &recipient.user_id == user_id {
+        return Err(ClcError::TargetIsSelf)
+    }
+    debug!("{} sent a direct message to {}", user_id, recipient.user_id);
+    // the sender gets a copy as confirmation
+    let message = ServerWsMessage::DirectMessage(user_id.clone(), sender.user_name.clone(), target, content);
+    send_msg(recipient, message.clone()).await;
+    send_msg(sender, message).await;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use tokio::fs;
+use uuid::Uuid;
+use warp::http::header::CONTENT_DISPOSITION;
+use warp::hyper::body::Bytes;
+use warp::{reply::json, Filter, Rejection, Reply};
+use clc_lib::protocol::{ChatId, FileId, FileInfo, FileName, Response, ServerEvent, ServerUploadResponse, ServerWsMessage, SessionToken, UserId};
+use crate::{Chat, Chats, Config, Result, Sessions, debug, error};
+use crate::chat::{broadcast_msg, user_name, ChatCommand, ChatContext};
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct UploadQuery {
+    name: FileName,
+}
+
+// the session token is sent as "Authorization: Bearer <token>", so it doesn't end up in urls and logs,
+// a missing token is treated like an unknown one
+pub(crate) fn session_token() -> impl Filter<Extract = (SessionToken,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(|header: Option<String>| {
+        header.and_then(|header| header.strip_prefix("Bearer ").map(|token| token.trim().to_string())).unwrap_or_default()
+    })
+}
+
+// files can only be shared and fetched by users with an open websocket
+async fn session_user(token: &SessionToken, sessions: &Sessions) -> Option<UserId> {
+    match sessions.read().await.get(token) {
+        Some(session) if session.connected => Some(session.user_id.clone()),
+        _ => None
+    }
+}
+
+fn chat_dir(chat_id: &ChatId, config: &Config) -> PathBuf {
+    config.files.path.join(chat_id)
+}
+
+// files are stored by id, the name is only handed back to clients, which write it to disk
+fn clean_file_name(name: &str) -> Option<FileName> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." || name.len() > 255 || name.chars().any(|c| c.is_control() || c == '"') {
+        return None
+    }
+    Some(name.to_string())
+}
+
+async fn write_file(dir: &Path, path: &Path, content: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    fs::write(path, content).await
+}
+
+pub(crate) async fn upload(token: SessionToken, chat_id: ChatId, query: UploadQuery, body: Bytes, chats: Chats, sessions: Sessions, config: Config) -> Result<impl Reply> {
+    if !config.features.files {
+        return Ok(json(&Response::<ServerUploadResponse>::Fail("file sharing is disabled on this server".to_string())))
+    }
+    let handle = chats.read().await.get(&chat_id).cloned();
+    let (user_id, handle) = match (session_user(&token, &sessions).await, handle) {
+        (Some(user_id), Some(handle)) if handle.ask(ChatCommand::Members).await.is_some_and(|members| members.contains(&user_id)) => (user_id, handle),
+        _ => return Ok(json(&Response::<ServerUploadResponse>::Fail("you are not a member of this chat".to_string())))
+    };
+    let name = match clean_file_name(&query.name) {
+        Some(name) => name,
+        None => return Ok(json(&Response::<ServerUploadResponse>::Fail("file name is not valid".to_string())))
+    };
+
+    // write under a temporary name first, the chat keeps running during the write
+    let dir = chat_dir(&chat_id, &config);
+    let tmp = dir.join(format!("{}.tmp", Uuid::new_v4().as_simple()));
+    if let Err(e) = write_file(&dir, &tmp, &body).await {
+        error!("unable to write {}: {}", tmp.display(), e);
+        return Ok(json(&Response::<ServerUploadResponse>::Fail("unable to store file".to_string())))
+    }
+
+    let size = body.len() as u64;
+    let shared = handle.ask(|reply| ChatCommand::ShareFile(user_id, tmp.clone(), name, size, reply)).await;
+    match shared {
+        Some(Ok(file_id)) => Ok(json(&Response::Accept(ServerUploadResponse(file_id)))),
+        Some(Err(reason)) => Ok(json(&Response::<ServerUploadResponse>::Fail(reason))),
+        // the chat was disbanded in the meantime
+        None => {
+            let _ = fs::remove_file(&tmp).await;
+            Ok(json(&Response::<ServerUploadResponse>::Fail("you are not a member of this chat".to_string())))
+        }
+    }
+}
+
+// run by the chat, the temporary file is moved into place or removed
+pub(crate) async fn share_file(chat: &mut Chat, user_id: &UserId, tmp: PathBuf, name: FileName, size: u64, context: &ChatContext) -> std::result::Result<FileId, String> {
+    if !chat.users.contains(user_id) {
+        let _ = fs::remove_file(&tmp).await;
+        return Err("you are not a member of this chat".to_string())
+    }
+    let file_id = chat.files.last().map(|f| f.0 + 1).unwrap_or(0);
+    if let Err(e) = fs::rename(&tmp, chat_dir(&chat.chat_id, &context.config).join(file_id.to_string())).await {
+        error!("unable to move {}: {}", tmp.display(), e);
+        let _ = fs::remove_file(&tmp).await;
+        return Err("unable to store file".to_string())
+    }
+    let user_name = user_name(user_id, &context.clients, &context.store).await;
+    let file = FileInfo(file_id, name, size, user_name);
+    chat.files.push(file.clone());
+    context.store.lock().await.save_chat(chat);
+    debug!("{} shared file {} in {}", user_id, file_id, chat.chat_id);
+    broadcast_msg(ServerWsMessage::SystemEvent(ServerEvent::FileShared(chat.chat_id.clone(), file)), chat);
+    Ok(file_id)
+}
+
+pub(crate) async fn download(token: SessionToken, chat_id: ChatId, file_id: FileId, chats: Chats, sessions: Sessions, config: Config) -> Result<impl Reply> {
+    if !config.features.files {
+        return Err(warp::reject::not_found())
+    }
+    let user_id = session_user(&token, &sessions).await.ok_or_else(warp::reject::not_found)?;
+    let handle = chats.read().await.get(&chat_id).cloned().ok_or_else(warp::reject::not_found)?;
+    let name = handle.ask(|reply| ChatCommand::FileName(user_id, file_id, reply)).await
+        .flatten()
+        .ok_or_else(warp::reject::not_found)?;
+    let path = chat_dir(&chat_id, &config).join(file_id.to_string());
+    let content = match fs::read(&path).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("unable to read {}: {}", path.display(), e);
+            return Err(warp::reject::not_found())
+        }
+    };
+    Ok(warp::reply::with_header(content, CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)))
+}
+
+pub(crate) async fn remove_chat_files(chat_id: &ChatId, config: &Config) {
+    let dir = chat_dir(chat_id, config);
+    if let Err(e) = fs::remove_dir_all(&dir).await {
+        if e.kind() != ErrorKind::NotFound {
+            error!("unable to remove {}: {}", dir.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_stay_in_directory() {
+        assert_eq!(clean_file_name("notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(clean_file_name("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(clean_file_name("C:\\Users\\bob\\cat.png").as_deref(), Some("cat.png"));
+        assert!(clean_file_name("..").is_none());
+        assert!(clean_file_name("dir/").is_none());
+        assert!(clean_file_name("a\"b").is_none());
+    }
+}
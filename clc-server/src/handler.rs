@@ -1,68 +1,171 @@
-use crate::{ws, Client, Clients, Result, debug, Chats, SERVER_VERSION};
-use clc_lib::protocol::{Response, ServerConnectRequest, ServerConnectResponse, ServerDisconnectRequest, ServerDisconnectResponse, UserId, UserName};
-use serde::{Deserialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::{ws, Account, Client, Clients, Config, Result, debug, error, Chats, Logins, Registrations, Sessions, Store, Throttles, SERVER_VERSION};
+use clc_lib::deserialize;
+use clc_lib::protocol::{Feature, Handshake, Reason, Response, ServerConnectRequest, ServerConnectResponse, ServerDisconnectRequest, ServerDisconnectResponse, ServerRegisterRequest, ServerRegisterResponse, SessionToken, UserId, UserName};
+use serde::Deserialize;
 use uuid::Uuid;
+use warp::hyper::body::Bytes;
+use warp::http::StatusCode;
 use warp::{reply::json, Reply};
-use clc_lib::validator::is_valid_name;
-use crate::chat::leave_chat;
-
-#[derive(Deserialize, Debug)]
-pub(crate) struct Event {
-    topic: String,
-    user_id: Option<usize>,
-    message: String,
+use crate::auth::{create_session, expire_unused_session, hash_password, verify_password};
+use crate::chat::leave_all_chats;
+use crate::names::taken_on_register;
+use crate::rates::{take_address, take_throttle, Throttle};
+
+// what this server supports, the features depend on the config
+pub(crate) fn handshake(config: &Config) -> Handshake {
+    let mut features = vec![Feature::DirectMessages];
+    if config.features.history {
+        features.push(Feature::History);
+    }
+    if config.features.files {
+        features.push(Feature::Files);
+    }
+    Handshake::new(features)
 }
 
-pub(crate) async fn register(body: ServerConnectRequest, clients: Clients) -> Result<impl Reply> {
-    let name = body.0.trim().to_string() as UserName;
+// requests of clients from before the handshake can't be parsed, they are told to update instead of getting a 400
+fn parse<T: for<'a> Deserialize<'a>>(body: &Bytes) -> std::result::Result<T, Reason> {
+    let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+    deserialize(body).map_err(|_| "unsupported client, please update it".to_string())
+}
 
-    if name.len() < 3 || name.len() > 16 {
-        return Ok(json(&Response::<ServerConnectResponse>::Fail(format!("name should be between 3 and 16 characters long, found {}", name.len()))))
+pub(crate) async fn register(body: Bytes, addr: Option<SocketAddr>, registrations: Registrations, store: Store, config: Config) -> Result<impl Reply> {
+    let ServerRegisterRequest(name, password, client_handshake) = match parse(&body) {
+        Ok(request) => request,
+        Err(reason) => return Ok(json(&Response::<ServerRegisterResponse>::Fail(reason)))
+    };
+    let server_handshake = handshake(&config);
+    if client_handshake.agree(&server_handshake).is_none() {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail(Handshake::refusal(&client_handshake, &server_handshake))))
     }
 
-    if !is_valid_name(&name) {
-        return Ok(json(&Response::<ServerConnectResponse>::Fail(format!("name is not valid"))))
+    if !config.features.registration {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail("registration is disabled on this server".to_string())))
     }
 
-    let uuid = Uuid::new_v4().as_simple().to_string();
+    let name = name.trim().to_string() as UserName;
+
+    if let Err(invalid) = config.limits.check_name(&name) {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail(format!("name {}", invalid))))
+    }
 
-    register_client(uuid.clone(), name, clients).await;
+    if let Err(invalid) = config.limits.check_password(&password) {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail(format!("password {}", invalid))))
+    }
+
+    if taken_on_register(&name, store.lock().await.as_ref(), &config) {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail(format!("name {} is already taken", name))))
+    }
+
+    // checked after the request was validated, so typos and taken names do not use up the limit
+    if let Some(addr) = addr {
+        if let Err(e) = take_address(addr.ip(), &registrations, &config.rates.registrations).await {
+            return Ok(json(&Response::<ServerRegisterResponse>::Fail(e.to_string())))
+        }
+    }
+
+    let password_hash = match hash_password(&password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("error hashing password: {}", e);
+            return Ok(json(&Response::<ServerRegisterResponse>::Fail("unable to create account".to_string())))
+        }
+    };
+
+    // checked again, someone else may have taken the name while the password was hashed
+    let mut store_w = store.lock().await;
+    if taken_on_register(&name, store_w.as_ref(), &config) {
+        return Ok(json(&Response::<ServerRegisterResponse>::Fail(format!("name {} is already taken", name))))
+    }
+    let uuid = Uuid::new_v4().as_simple().to_string();
+    store_w.save_account(&Account { user_id: uuid.clone(), user_name: name, password_hash, nick: None });
     debug!("{} registered", uuid);
-    Ok(json(&Response::Accept(ServerConnectResponse(uuid, SERVER_VERSION.to_string()))))
+    Ok(json(&Response::Accept(ServerRegisterResponse(uuid))))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn connect(body: Bytes, addr: Option<SocketAddr>, logins: Logins, clients: Clients, sessions: Sessions, throttles: Throttles, store: Store, config: Config) -> Result<impl Reply> {
+    let ServerConnectRequest(name, password, client_handshake) = match parse(&body) {
+        Ok(request) => request,
+        Err(reason) => return Ok(json(&Response::<ServerConnectResponse>::Fail(reason)))
+    };
+    let server_handshake = handshake(&config);
+    let negotiated = match server_handshake.agree(&client_handshake) {
+        Some(negotiated) => negotiated,
+        None => return Ok(json(&Response::<ServerConnectResponse>::Fail(Handshake::refusal(&client_handshake, &server_handshake))))
+    };
+
+    // before the password is checked, so guessing it is slow and hashing can't be used to load the server
+    if let Some(addr) = addr {
+        if let Err(e) = take_address(addr.ip(), &logins, &config.rates.logins).await {
+            return Ok(json(&Response::<ServerConnectResponse>::Fail(e.to_string())))
+        }
+    }
+
+    let account = store.lock().await.find_account(&name.trim().to_string());
+    let account = match account {
+        Some(account) if verify_password(&password, &account.password_hash) => account,
+        _ => return Ok(json(&Response::<ServerConnectResponse>::Fail("wrong name or password".to_string())))
+    };
+
+    if clients.read().await.get(&account.user_id).is_some_and(|c| c.sender.is_some()) {
+        return Ok(json(&Response::<ServerConnectResponse>::Fail("you are already connected".to_string())))
+    }
+
+    let token = create_session(&account.user_id, negotiated.0, &sessions).await;
+    let name = account.display_name().clone();
+    let throttle = take_throttle(&account.user_id, &throttles, &config.rates).await;
+    register_client(account.user_id.clone(), name.clone(), throttle, &clients).await;
+    tokio::spawn(expire_unused_session(token.clone(), clients.clone(), sessions.clone()));
+    debug!("{} logged in with protocol {:?}", account.user_id, negotiated);
+    Ok(json(&Response::Accept(ServerConnectResponse(account.user_id, token, name, SERVER_VERSION.to_string(), negotiated))))
 }
 
-async fn register_client(user_id: UserId, name: UserName, clients: Clients) {
+async fn register_client(user_id: UserId, name: UserName, throttle: Arc<Mutex<Throttle>>, clients: &Clients) {
     clients.write().await.insert(
         user_id.clone(),
         Client {
             user_id,
             user_name: name,
-            chat: None,
             sender: None,
+            throttle,
         },
     );
 }
 
-pub(crate) async fn unregister(request: ServerDisconnectRequest, clients: Clients, chats: Chats) -> Result<impl Reply> {
-    if let Some(client) = clients.write().await.remove(&request.0){
-        debug!("{} unregistered", request.0);
-        if let Client { chat: Some(_), .. } = client.clone() {
-            leave_chat(&request.0, &clients, &chats).await;
-        }
+pub(crate) async fn disconnect(request: ServerDisconnectRequest, clients: Clients, chats: Chats, sessions: Sessions) -> Result<impl Reply> {
+    let session = sessions.write().await.remove(&request.0);
+    if let Some(session) = session {
+        // leave before removing the client, so the others still see its name
+        leave_all_chats(&session.user_id, &chats).await;
+        clients.write().await.remove(&session.user_id);
+        debug!("{} logged out", session.user_id);
         Ok(json(&Response::Accept(ServerDisconnectResponse())))
     }
     else{
-        Ok(json(&Response::<ServerDisconnectResponse>::Fail(format!("Invalid user id"))))
+        Ok(json(&Response::<ServerDisconnectResponse>::Fail("Invalid session".to_string())))
     }
 }
 
-pub(crate) async fn ws_handler(ws: warp::ws::Ws, id: UserId, clients: Clients, chats: Chats) -> Result<impl Reply> {
-    let client = clients.read().await.get(&id).cloned();
-    match client {
-        Some(c) => {
-            debug!("Created websocket connection for {}", c.user_id);
-            Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, chats)))
+pub(crate) async fn ws_handler(ws: warp::ws::Ws, token: SessionToken, clients: Clients, chats: Chats, sessions: Sessions, store: Store, config: Config) -> Result<impl Reply> {
+    let user_id = match sessions.read().await.get(&token) {
+        // the old connection might be dead without the server knowing yet, the client can retry once pings noticed it
+        Some(session) if session.connected => return Ok(StatusCode::CONFLICT.into_response()),
+        // marked connected by the connection itself once the upgrade happened
+        Some(session) if session.can_connect() => {
+            debug!("opening websocket for {} with protocol {}", session.user_id, session.protocol);
+            session.user_id.clone()
         }
-        None => Err(warp::reject::not_found()),
+        _ => return Err(warp::reject::not_found())
+    };
+    if clients.read().await.contains_key(&user_id) {
+        debug!("Created websocket connection for {}", user_id);
+        let ws = ws.max_frame_size(config.connection.max_frame_size).max_message_size(config.connection.max_frame_size);
+        Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, user_id, token, clients, chats, sessions, store, config)).into_response())
+    } else {
+        Err(warp::reject::not_found())
     }
-}
\ No newline at end of file
+}
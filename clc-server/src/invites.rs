@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+use clc_lib::protocol::{ClcError, InviteId, InviteInfo, Seconds, ServerWsMessage, UserId};
+use crate::{Chat, WsResult, debug};
+use crate::chat::{ChatContext, send_to, user_name};
+use crate::moderation::until;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Invite {
+    pub(crate) creator: UserId,
+    // None means unlimited
+    pub(crate) uses_left: Option<u32>,
+    // None means never
+    pub(crate) expires: Option<SystemTime>,
+}
+
+pub(crate) type Invites = HashMap<InviteId, Invite>;
+
+// invites valid for longer should rather never expire
+const MAX_VALID_FOR: Seconds = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredInvites {
+    // invites used to be single use and never expire, their creator wasn't kept
+    Ids(HashSet<InviteId>),
+    Invites(Invites)
+}
+
+pub(crate) fn deserialize_invites<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Invites, D::Error> {
+    Ok(match StoredInvites::deserialize(deserializer)? {
+        StoredInvites::Ids(ids) => ids.into_iter()
+            .map(|id| (id, Invite { creator: UserId::new(), uses_left: Some(1), expires: None }))
+            .collect(),
+        StoredInvites::Invites(invites) => invites
+    })
+}
+
+fn remove_expired(invites: &mut Invites) {
+    let now = SystemTime::now();
+    invites.retain(|_, invite| invite.expires.is_none_or(|expires| expires > now));
+}
+
+// expired invites are removed on access
+pub(crate) fn is_valid(invites: &mut Invites, invite_id: &InviteId) -> bool {
+    remove_expired(invites);
+    invites.contains_key(invite_id)
+}
+
+// the invite is removed once it has no uses left
+pub(crate) fn redeem(invites: &mut Invites, invite_id: &InviteId) {
+    if let Some(invite) = invites.get_mut(invite_id) {
+        match &mut invite.uses_left {
+            Some(1) => {
+                invites.remove(invite_id);
+            }
+            Some(uses) => *uses -= 1,
+            None => {}
+        }
+    }
+}
+
+fn expires_in(invite: &Invite) -> Option<Seconds> {
+    invite.expires.map(|expires| expires.duration_since(SystemTime::now()).unwrap_or_default().as_secs())
+}
+
+pub(crate) async fn create_invite(chat: &mut Chat, user_id: &UserId, uses: Option<u32>, valid_for: Option<Seconds>, context: &ChatContext) -> WsResult {
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    // an invite without uses would be useless, so 0 means unlimited like None
+    let uses_left = uses.filter(|uses| *uses > 0);
+    if let Some(secs) = valid_for.filter(|secs| *secs > MAX_VALID_FOR) {
+        return Err(ClcError::InvalidDuration(secs))
+    }
+    let expires = until(valid_for)?;
+    let invite_id = Uuid::new_v4().as_simple().to_string();
+    let uses = match uses_left {
+        Some(1) => "single use".to_string(),
+        Some(uses) => format!("{} uses", uses),
+        None => "unlimited uses".to_string()
+    };
+    let valid = match valid_for {
+        Some(secs) => format!("valid for {} minutes", secs / 60),
+        None => "never expires".to_string()
+    };
+    debug!("{} created invite {} for {}", user_id, invite_id, chat.chat_id);
+    send_to(user_id, ServerWsMessage::SystemMessage(format!("Created invite for {}: {} ({}, {})", chat.title, invite_id, uses, valid)), &context.clients).await;
+    remove_expired(&mut chat.invites);
+    chat.invites.insert(invite_id, Invite { creator: user_id.clone(), uses_left, expires });
+    context.store.lock().await.save_chat(chat);
+    Ok(())
+}
+
+pub(crate) async fn revoke_invite(chat: &mut Chat, user_id: &UserId, invite_id: InviteId, context: &ChatContext) -> WsResult {
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    if !is_valid(&mut chat.invites, &invite_id) {
+        return Err(ClcError::InvalidInvite)
+    }
+    chat.invites.remove(&invite_id);
+    context.store.lock().await.save_chat(chat);
+    debug!("{} revoked invite {} of {}", user_id, invite_id, chat.chat_id);
+    send_to(user_id, ServerWsMessage::SystemMessage(format!("Revoked invite {}", invite_id)), &context.clients).await;
+    Ok(())
+}
+
+pub(crate) async fn list_invites(chat: &mut Chat, user_id: &UserId, context: &ChatContext) -> WsResult {
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    remove_expired(&mut chat.invites);
+    let mut invites = Vec::new();
+    for (invite_id, invite) in chat.invites.iter() {
+        let creator = if invite.creator.is_empty() {
+            "unknown".to_string()
+        } else {
+            user_name(&invite.creator, &context.clients, &context.store).await
+        };
+        invites.push(InviteInfo(invite_id.clone(), creator, invite.uses_left, expires_in(invite)));
+    }
+    send_to(user_id, ServerWsMessage::Invites(chat.chat_id.clone(), invites), &context.clients).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Stored {
+        #[serde(deserialize_with = "deserialize_invites")]
+        invites: Invites
+    }
+
+    #[test]
+    fn invites_are_used_up() {
+        let Stored { mut invites } = clc_lib::deserialize(r#"{"invites": ["i1"]}"#).unwrap();
+        invites.insert("i2".to_string(), Invite { creator: "u1".to_string(), uses_left: Some(2), expires: None });
+        invites.insert("i3".to_string(), Invite { creator: "u1".to_string(), uses_left: None, expires: until(Some(0)).unwrap() });
+        let id = |id: &str| id.to_string();
+        assert!(!is_valid(&mut invites, &id("i3")));
+        redeem(&mut invites, &id("i1"));
+        assert!(!is_valid(&mut invites, &id("i1")));
+        redeem(&mut invites, &id("i2"));
+        assert!(is_valid(&mut invites, &id("i2")));
+        redeem(&mut invites, &id("i2"));
+        assert!(invites.is_empty());
+    }
+}
@@ -1,60 +1,234 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use warp::{ws::Message, Filter, Rejection};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use warp::{Filter, Rejection};
 use warp::http::StatusCode;
-use clc_lib::protocol::{ChatId, ChatTitle, InviteId, ServerVersion, UserId, UserName};
+use clc_lib::validator::skeleton;
+use clc_lib::protocol::{ChatId, ClcError, ChatMessage, ChatTitle, FileId, FileInfo, ServerVersion, SessionToken, UserId, UserName, Visibility};
+use crate::auth::Session;
+use crate::chat::{spawn_chat, ChatContext, ChatHandle, Delivery};
+use crate::config::{set_log_level, Args, ServerConfig};
+use crate::invites::{deserialize_invites, Invites};
+use crate::moderation::Restrictions;
+use crate::rates::{Bucket, Throttle};
+use crate::storage::{FileStorage, MemoryStorage, Storage};
+use crate::ws::Outbox;
 
 mod handler;
 mod ws;
 mod chat;
+mod storage;
+mod auth;
+mod config;
+mod moderation;
+mod files;
+mod direct;
+mod names;
+mod invites;
+mod rates;
 
-#[cfg(debug_assertions)]
 #[macro_export]
-macro_rules! debug {
-    () => {
-        println!()
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::config::log_enabled($crate::config::LogLevel::Error) {
+            eprintln!($($arg)*)
+        }
     };
+}
+
+#[macro_export]
+macro_rules! info {
     ($($arg:tt)*) => {
-        println!($($arg)*)
+        if $crate::config::log_enabled($crate::config::LogLevel::Info) {
+            println!($($arg)*)
+        }
     };
 }
 
-#[cfg(not(debug_assertions))]
 #[macro_export]
 macro_rules! debug {
-    () => {};
-    ($($arg:tt)*) => {};
+    ($($arg:tt)*) => {
+        if $crate::config::log_enabled($crate::config::LogLevel::Debug) {
+            println!($($arg)*)
+        }
+    };
 }
 
 type Result<T> = std::result::Result<T, Rejection>;
+// outcome of a websocket request, sent back as reply
+type WsResult = std::result::Result<(), ClcError>;
 type Clients = Arc<RwLock<HashMap<UserId, Client>>>;
-type Chats = Arc<RwLock<HashMap<ChatId, Chat>>>;
+type Chats = Arc<RwLock<ChatMap>>;
+type Sessions = Arc<RwLock<HashMap<SessionToken, Session>>>;
+type Store = Arc<Mutex<Box<dyn Storage>>>;
+type Config = Arc<ServerConfig>;
+type Registrations = Arc<Mutex<HashMap<IpAddr, Bucket>>>;
+// login attempts, so passwords can't be guessed quickly
+type Logins = Arc<Mutex<HashMap<IpAddr, Bucket>>>;
+// kept across logins, so logging in again doesn't lift limits or mutes
+type Throttles = Arc<Mutex<HashMap<UserId, Arc<Mutex<Throttle>>>>>;
 
-const SERVER_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, Clone)]
 pub(crate) struct Client {
     pub(crate) user_id: UserId,
     pub(crate) user_name: UserName,
-    pub(crate) chat: Option<ChatId>,
-    pub(crate) sender: Option<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>,
+    pub(crate) sender: Option<Outbox>,
+    pub(crate) throttle: Arc<Mutex<Throttle>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Account {
+    pub(crate) user_id: UserId,
+    // used to log in
+    pub(crate) user_name: UserName,
+    pub(crate) password_hash: String,
+    // shown to others instead of the user name if set
+    #[serde(default)]
+    pub(crate) nick: Option<UserName>,
+}
+
+impl Account {
+    pub(crate) fn display_name(&self) -> &UserName {
+        self.nick.as_ref().unwrap_or(&self.user_name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Chat {
     pub(crate) chat_id: ChatId,
     pub(crate) title: ChatTitle,
     pub(crate) owner: UserId,
+    // the owner is always admin, but not part of this set
+    #[serde(default)]
+    pub(crate) admins: HashSet<UserId>,
     pub(crate) users: HashSet<UserId>,
-    pub(crate) invites: HashSet<InviteId>,
+    #[serde(deserialize_with = "deserialize_invites")]
+    pub(crate) invites: Invites,
+    #[serde(default)]
+    pub(crate) history: VecDeque<ChatMessage>,
+    #[serde(default)]
+    pub(crate) banned: Restrictions,
+    #[serde(default)]
+    pub(crate) muted: Restrictions,
+    #[serde(default)]
+    pub(crate) files: Vec<FileInfo>,
+    #[serde(default)]
+    pub(crate) visibility: Visibility,
+    #[serde(default)]
+    pub(crate) topic: Option<String>,
+    // set while the chat is running, its members' connections subscribe to it
+    #[serde(skip)]
+    pub(crate) fanout: Option<broadcast::Sender<Delivery>>,
+    // limits the messages of all members together
+    #[serde(skip)]
+    pub(crate) message_rate: Bucket,
+    // messages were added since the chat was last saved, the chat task saves them periodically
+    #[serde(skip)]
+    pub(crate) unsaved: bool,
+}
+
+impl Chat {
+    pub(crate) fn is_admin(&self, user_id: &UserId) -> bool {
+        &self.owner == user_id || self.admins.contains(user_id)
+    }
+}
+
+// handles of the running chats by id, with an index of their titles so they can be joined by title as well,
+// titles that only differ in case or look-alike letters count as equal
+#[derive(Default)]
+pub(crate) struct ChatMap {
+    chats: HashMap<ChatId, ChatHandle>,
+    titles: HashMap<String, ChatId>
+}
+
+impl ChatMap {
+    pub(crate) fn get(&self, chat_id: &ChatId) -> Option<&ChatHandle> {
+        self.chats.get(chat_id)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&ChatId, &ChatHandle)> {
+        self.chats.iter()
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &ChatHandle> {
+        self.chats.values()
+    }
+
+    pub(crate) fn title_taken(&self, title: &ChatTitle) -> bool {
+        self.titles.contains_key(&skeleton(title))
+    }
+
+    // ids take precedence, so a chat can't be shadowed by a title looking like an id
+    pub(crate) fn find(&self, title_or_id: &str) -> Option<ChatId> {
+        if self.chats.contains_key(title_or_id) {
+            return Some(title_or_id.to_string())
+        }
+        self.titles.get(&skeleton(title_or_id)).cloned()
+    }
+
+    // chats stored before titles were unique keep a title that can't be used to join them
+    pub(crate) fn insert(&mut self, chat_id: ChatId, handle: ChatHandle) {
+        match self.titles.entry(skeleton(&handle.title)) {
+            Entry::Vacant(entry) => {
+                entry.insert(chat_id.clone());
+            }
+            Entry::Occupied(_) => info!("title of chat {} is already taken, it can only be joined by id", chat_id)
+        }
+        self.chats.insert(chat_id, handle);
+    }
+
+    pub(crate) fn remove(&mut self, chat_id: &ChatId) -> Option<ChatHandle> {
+        let handle = self.chats.remove(chat_id)?;
+        let key = skeleton(&handle.title);
+        if self.titles.get(&key) == Some(chat_id) {
+            self.titles.remove(&key);
+        }
+        Some(handle)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.chats.len()
+    }
+}
+
+// startup errors are the user's to fix, so they get a message instead of a panic
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    error!("{}", e);
+    std::process::exit(1)
 }
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    if let Some(level) = args.log_level {
+        set_log_level(level);
+    }
+    let config: Config = Arc::new(ServerConfig::load(&args.config).unwrap_or_else(|e| exit_with(e)));
+    debug!("loaded config {:?}", config);
+
+    let storage: Box<dyn Storage> = if config.storage == "memory" {
+        Box::<MemoryStorage>::default()
+    } else {
+        Box::new(FileStorage::open(&config.storage).unwrap_or_else(|e| exit_with(e)))
+    };
+    let stored_chats = storage.chats();
     let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
-    let chats: Chats = Arc::new(RwLock::new(HashMap::new()));
+    let chats: Chats = Arc::new(RwLock::new(ChatMap::default()));
+    let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+    let registrations: Registrations = Arc::new(Mutex::new(HashMap::new()));
+    let logins: Logins = Arc::new(Mutex::new(HashMap::new()));
+    let throttles: Throttles = Arc::new(Mutex::new(HashMap::new()));
+    let store: Store = Arc::new(Mutex::new(storage));
+    tokio::spawn(storage::flush_periodically(store.clone(), chats.clone(), config.flush_interval));
+    let context = ChatContext { clients: clients.clone(), chats: chats.clone(), store: store.clone(), config: config.clone() };
+    start_chats(stored_chats, &context).await;
 
     // auto-loads https://github.com/DragonFIghter603/command-line-chat/blob/master/index.html
     let index_route = warp::path!().and_then(|| async {
@@ -63,43 +237,138 @@ async fn main() {
 
     let health_route = warp::path!("api"/"health").and_then(|| async { Ok::<_, Rejection>(StatusCode::OK) });
     let version_route = warp::path!("api"/"version")
-        .and_then(|| async { Ok::<_, Rejection>(warp::reply::json(&ServerVersion(SERVER_VERSION.to_string()))) });
+        .and(with(config.clone()))
+        .and_then(|config: Config| async move {
+            Ok::<_, Rejection>(warp::reply::json(&ServerVersion(SERVER_VERSION.to_string(), handler::handshake(&config), config.limits.clone())))
+        });
 
-    let register = warp::path!("api"/"register");
-    let register_routes = register
+    let register_route = warp::path!("api"/"register")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(warp::body::content_length_limit(config.connection.max_body_size))
+        .and(warp::body::bytes())
+        .and(warp::addr::remote())
+        .and(with(registrations.clone()))
+        .and(with(store.clone()))
+        .and(with(config.clone()))
+        .and_then(handler::register);
+
+    let login = warp::path!("api"/"login");
+    let login_routes = login
+        .and(warp::post())
+        .and(warp::body::content_length_limit(config.connection.max_body_size))
+        .and(warp::body::bytes())
+        .and(warp::addr::remote())
+        .and(with(logins.clone()))
         .and(with(clients.clone()))
-        .and_then(handler::register)
-        .or(register
+        .and(with(sessions.clone()))
+        .and(with(throttles.clone()))
+        .and(with(store.clone()))
+        .and(with(config.clone()))
+        .and_then(handler::connect)
+        .or(login
             .and(warp::delete())
+            .and(warp::body::content_length_limit(config.connection.max_body_size))
             .and(warp::body::json())
             .and(with(clients.clone()))
             .and(with(chats.clone()))
-            .and_then(handler::unregister));
+            .and(with(sessions.clone()))
+            .and_then(handler::disconnect));
 
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(warp::path::param())
         .and(with(clients.clone()))
         .and(with(chats.clone()))
+        .and(with(sessions.clone()))
+        .and(with(store.clone()))
+        .and(with(config.clone()))
         .and_then(handler::ws_handler);
 
+    let files = warp::path!("api"/"files"/..);
+    let file_routes = files
+        .and(files::session_token())
+        .and(warp::path!(ChatId))
+        .and(warp::post())
+        .and(warp::query())
+        .and(warp::body::content_length_limit(config.files.max_size))
+        .and(warp::body::bytes())
+        .and(with(chats.clone()))
+        .and(with(sessions.clone()))
+        .and(with(config.clone()))
+        .and_then(files::upload)
+        .or(files
+            .and(files::session_token())
+            .and(warp::path!(ChatId / FileId))
+            .and(warp::get())
+            .and(with(chats.clone()))
+            .and(with(sessions.clone()))
+            .and(with(config.clone()))
+            .and_then(files::download));
+
     let routes = index_route
         .or(health_route)
         .or(version_route)
-        .or(register_routes)
+        .or(register_route)
+        .or(login_routes)
+        .or(file_routes)
         .or(ws_route)
         .with(warp::cors().allow_any_origin());
 
-    warp::serve(routes)
-        //.tls()
-        //.cert_path("tls/cert.pem")
-        //.key_path("tls/key.rsa")
+    let addr = SocketAddr::new(args.bind, args.port);
+    // clap makes sure cert and key are either both set or both missing
+    match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            for path in [&cert, &key] {
+                if !path.exists() {
+                    exit_with(format!("TLS file {} does not exist", path.display()));
+                }
+            }
+            info!("serving https/wss on {} with certificate {}", addr, cert.display());
+            warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .run(addr).await;
+        }
+        _ => {
+            info!("serving plain http/ws on {}", addr);
+            warp::serve(routes).run(addr).await;
+        }
+    }
+}
 
-        .run(([0, 0, 0, 0], 10000)).await;
+async fn start_chats(stored: Vec<Chat>, context: &ChatContext) {
+    let mut chats = context.chats.write().await;
+    for chat in stored {
+        chats.insert(chat.chat_id.clone(), spawn_chat(chat, context.clone()));
+    }
+    debug!("loaded {} chats", chats.len());
 }
 
 fn with<T: Clone + Send>(data: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
     warp::any().map(move || data.clone())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn handle(title: &str) -> ChatHandle {
+        ChatHandle { title: title.to_string(), sender: mpsc::unbounded_channel().0 }
+    }
+
+    #[test]
+    fn chats_are_found_by_title_or_id() {
+        let mut chats = ChatMap::default();
+        chats.insert("c1".to_string(), handle("General"));
+        // stored before titles were unique
+        chats.insert("c2".to_string(), handle("general"));
+        assert_eq!(chats.find("c2"), Some("c2".to_string()));
+        assert_eq!(chats.find("GENERAL"), Some("c1".to_string()));
+        assert!(chats.title_taken(&"g\u{0435}neral".to_string()));
+        chats.remove(&"c2".to_string());
+        assert_eq!(chats.find("general"), Some("c1".to_string()));
+        chats.remove(&"c1".to_string());
+        assert_eq!(chats.find("general"), None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use clc_lib::protocol::{ClcError, ModAction, Role, Seconds, ServerEvent, ServerWsMessage, UserId, UserName};
+use crate::{Chat, WsResult, debug};
+use crate::chat::{ChatContext, broadcast_msg, send_to, unsubscribe};
+use crate::names::find_user;
+
+// None means until pardoned
+pub(crate) type Restrictions = HashMap<UserId, Option<SystemTime>>;
+
+// fails for durations too long to be represented
+pub(crate) fn until(duration: Option<Seconds>) -> Result<Option<SystemTime>, ClcError> {
+    match duration {
+        Some(secs) => SystemTime::now().checked_add(Duration::from_secs(secs)).map(Some).ok_or(ClcError::InvalidDuration(secs)),
+        None => Ok(None)
+    }
+}
+
+// expired restrictions are removed on access
+pub(crate) fn is_restricted(restrictions: &mut Restrictions, user_id: &UserId) -> bool {
+    match restrictions.get(user_id) {
+        Some(Some(until)) if *until <= SystemTime::now() => {
+            restrictions.remove(user_id);
+            false
+        }
+        Some(_) => true,
+        None => false
+    }
+}
+
+pub(crate) async fn moderate(chat: &mut Chat, user_id: &UserId, target: UserName, action: ModAction, context: &ChatContext) -> WsResult {
+    let chat_id = chat.chat_id.clone();
+    let target_id = {
+        let clients_r = context.clients.read().await;
+        let store_r = context.store.lock().await;
+        find_user(&target, chat, &clients_r, store_r.as_ref()).ok_or_else(|| ClcError::UserNotFound(target.clone()))?
+    };
+    if !chat.is_admin(user_id) {
+        return Err(ClcError::NotAdmin)
+    }
+    if &target_id == user_id {
+        return Err(ClcError::TargetIsSelf)
+    }
+    // admins can only moderate members, the owner can moderate everyone
+    if user_id != &chat.owner && chat.is_admin(&target_id) {
+        return Err(ClcError::TargetIsAdmin(target))
+    }
+    match &action {
+        ModAction::Kick => if !chat.users.contains(&target_id) {
+            return Err(ClcError::TargetNotMember(target))
+        }
+        ModAction::Ban(duration) => {
+            chat.banned.insert(target_id.clone(), until(*duration)?);
+        }
+        ModAction::Mute(duration) => {
+            chat.muted.insert(target_id.clone(), until(*duration)?);
+        }
+        ModAction::Pardon => {
+            let banned = chat.banned.remove(&target_id).is_some();
+            let muted = chat.muted.remove(&target_id).is_some();
+            if !banned && !muted {
+                return Err(ClcError::TargetNotRestricted(target))
+            }
+        }
+    }
+    debug!("{} moderated {} in {}: {:?}", user_id, target_id, chat_id, action);
+    // the target gets notified as well before being removed
+    broadcast_msg(ServerWsMessage::SystemEvent(ServerEvent::Moderated(chat_id.clone(), target, action.clone())), chat);
+    if matches!(action, ModAction::Kick | ModAction::Ban(_)) && chat.users.remove(&target_id) {
+        chat.admins.remove(&target_id);
+        unsubscribe(chat, &target_id, Some(ServerWsMessage::SystemEvent(ServerEvent::ChatLeave(chat_id))));
+    }
+    context.store.lock().await.save_chat(chat);
+    Ok(())
+}
+
+pub(crate) async fn set_role(chat: &mut Chat, user_id: &UserId, target: UserName, role: Role, context: &ChatContext) -> WsResult {
+    let chat_id = chat.chat_id.clone();
+    let target_id = {
+        let clients_r = context.clients.read().await;
+        let store_r = context.store.lock().await;
+        find_user(&target, chat, &clients_r, store_r.as_ref()).ok_or_else(|| ClcError::UserNotFound(target.clone()))?
+    };
+    if user_id != &chat.owner {
+        return Err(ClcError::NotOwner)
+    }
+    if &target_id == user_id {
+        return Err(ClcError::TargetIsSelf)
+    }
+    if !chat.users.contains(&target_id) {
+        return Err(ClcError::TargetNotMember(target))
+    }
+    match role {
+        Role::Owner => {
+            // the previous owner stays on as admin
+            chat.admins.remove(&target_id);
+            chat.admins.insert(chat.owner.clone());
+            chat.owner = target_id.clone();
+        }
+        Role::Admin => if !chat.admins.insert(target_id.clone()) {
+            return Err(ClcError::AlreadyAdmin(target))
+        }
+        Role::Member => if !chat.admins.remove(&target_id) {
+            return Err(ClcError::TargetNotAdmin(target))
+        }
+    }
+    debug!("{} made {} {:?} in {}", user_id, target_id, role, chat_id);
+    broadcast_msg(ServerWsMessage::SystemEvent(ServerEvent::RoleChanged(chat_id.clone(), target, role.clone())), chat);
+    // a previous owner stays admin, so only the target's permissions change
+    send_to(&target_id, ServerWsMessage::SystemEvent(ServerEvent::SetAdmin(chat_id, role != Role::Member)), &context.clients).await;
+    context.store.lock().await.save_chat(chat);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restrictions_expire() {
+        let mut restrictions = Restrictions::new();
+        let user = "u1".to_string();
+        assert!(!is_restricted(&mut restrictions, &user));
+        restrictions.insert(user.clone(), None);
+        assert!(is_restricted(&mut restrictions, &user));
+        restrictions.insert(user.clone(), Some(SystemTime::now() - Duration::from_secs(1)));
+        assert!(!is_restricted(&mut restrictions, &user));
+        assert!(restrictions.is_empty());
+        restrictions.insert(user.clone(), until(Some(60)).unwrap());
+        assert!(is_restricted(&mut restrictions, &user));
+        assert_eq!(until(Some(u64::MAX / 60 * 60)), Err(ClcError::InvalidDuration(u64::MAX / 60 * 60)));
+    }
+}
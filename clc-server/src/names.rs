@@ -0,0 +1,82 @@
+use std::collections::{HashMap, HashSet};
+use clc_lib::protocol::{ClcError, ServerEvent, ServerWsMessage, UserId, UserName};
+use clc_lib::validator::skeleton;
+use crate::{Chat, Chats, Client, Clients, Config, Store, WsResult, debug};
+use crate::chat::{joined_chats, send_msg};
+use crate::config::NameScope;
+use crate::storage::Storage;
+
+// names that only differ in case or look-alike letters count as equal
+fn same_name(a: &str, b: &str) -> bool {
+    skeleton(a) == skeleton(b)
+}
+
+// the name others see, offline users are only known to the storage
+pub(crate) fn display_name(user_id: &UserId, clients: &HashMap<UserId, Client>, store: &dyn Storage) -> Option<UserName> {
+    match clients.get(user_id) {
+        Some(c) => Some(c.user_name.clone()),
+        None => store.account(user_id).map(|account| account.display_name().clone())
+    }
+}
+
+// account names are used to log in and have to be unique on the server in any case
+pub(crate) fn taken_on_register(name: &str, store: &dyn Storage, config: &Config) -> bool {
+    store.accounts().iter().any(|account| same_name(&account.user_name, name)
+        || (config.names.unique == NameScope::Server && same_name(account.display_name(), name)))
+}
+
+fn taken_on_server(user_id: &UserId, name: &str, store: &dyn Storage) -> bool {
+    store.accounts().iter()
+        .filter(|account| &account.user_id != user_id)
+        .any(|account| same_name(&account.user_name, name) || same_name(account.display_name(), name))
+}
+
+pub(crate) fn taken_in_chat(user_id: &UserId, name: &str, members: &HashSet<UserId>, clients: &HashMap<UserId, Client>, store: &dyn Storage) -> bool {
+    members.iter()
+        .filter(|member| *member != user_id)
+        .filter_map(|member| display_name(member, clients, store))
+        .any(|member_name| same_name(&member_name, name))
+}
+
+// members, banned and muted users are found by the name they are shown with, anyone else by their account name
+pub(crate) fn find_user(name: &UserName, chat: &Chat, clients: &HashMap<UserId, Client>, store: &dyn Storage) -> Option<UserId> {
+    chat.users.iter().chain(chat.banned.keys()).chain(chat.muted.keys())
+        .find(|user_id| display_name(user_id, clients, store).as_ref() == Some(name))
+        .cloned()
+        .or_else(|| store.find_account(name).map(|account| account.user_id))
+}
+
+pub(crate) async fn set_nick(user_id: &UserId, nick: UserName, clients: &Clients, chats: &Chats, store: &Store, config: &Config) -> WsResult {
+    config.limits.check_name(&nick).map_err(ClcError::InvalidName)?;
+    let joined = joined_chats(user_id, chats).await;
+    // clients and storage stay locked until the name is saved, so no one can take it in the meantime
+    let mut clients_w = clients.write().await;
+    let mut store_w = store.lock().await;
+    let taken = match config.names.unique {
+        NameScope::Server => taken_on_server(user_id, &nick, store_w.as_ref()),
+        NameScope::Chat => joined.iter().any(|members| taken_in_chat(user_id, &nick, members, &clients_w, store_w.as_ref()))
+    };
+    if taken {
+        return Err(ClcError::NameTaken(nick))
+    }
+    let mut account = store_w.account(user_id).expect("connected user without account");
+    let old = account.display_name().clone();
+    account.nick = Some(nick.clone());
+    store_w.save_account(&account);
+    drop(store_w);
+    if let Some(c) = clients_w.get_mut(user_id) {
+        c.user_name = nick.clone();
+    }
+    debug!("{} is now called {}", user_id, nick);
+
+    // everyone sharing a chat with the user is told once
+    let mut recipients: HashSet<&UserId> = joined.iter().flatten().collect();
+    recipients.insert(user_id);
+    let event = ServerWsMessage::SystemEvent(ServerEvent::NickChanged(user_id.clone(), old, nick));
+    for recipient in recipients {
+        if let Some(c) = clients_w.get(recipient) {
+            send_msg(c, event.clone()).await;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use clc_lib::protocol::{ClcError, ClientWsMessage, Seconds, UserId};
+use crate::{Throttles, WsResult};
+use crate::config::{Rate, Rates};
+
+// strikes older than this are forgotten
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+// token bucket, a new bucket is full
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    refilled: Option<Instant>,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let burst = rate.burst as f64;
+        self.tokens = match self.refilled {
+            Some(refilled) => (self.tokens + now.duration_since(refilled).as_secs_f64() * rate.per_minute as f64 / 60.0).min(burst),
+            None => burst
+        };
+        self.refilled = Some(now);
+    }
+
+    pub(crate) fn take(&mut self, rate: &Rate) -> WsResult {
+        self.take_at(rate, Instant::now())
+    }
+
+    // fails with the seconds until the next token
+    fn take_at(&mut self, rate: &Rate, now: Instant) -> WsResult {
+        if rate.burst == 0 {
+            return Ok(())
+        }
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(())
+        }
+        Err(ClcError::SlowDown(((1.0 - self.tokens) * 60.0 / rate.per_minute as f64).ceil() as Seconds))
+    }
+
+    fn is_full(&mut self, rate: &Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= rate.burst as f64
+    }
+}
+
+// limits of a user, kept while they are logged in
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    messages: Bucket,
+    chats: Bucket,
+    invites: Bucket,
+    // when the user was slowed down
+    strikes: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl Throttle {
+    // limits of chats are checked by the chats
+    pub(crate) fn admit(&mut self, message: &ClientWsMessage, rates: &Rates) -> WsResult {
+        let now = Instant::now();
+        match message {
+            ClientWsMessage::Message(..) | ClientWsMessage::DirectMessage(..) => {
+                if let Some(until) = self.muted_until.filter(|until| *until > now) {
+                    return Err(ClcError::SlowDown(until.duration_since(now).as_secs().max(1)))
+                }
+                self.messages.take_at(&rates.messages, now)
+            }
+            ClientWsMessage::ChatCreate(_) => self.chats.take_at(&rates.chat_creation, now),
+            ClientWsMessage::ChatCreateInvite(..) => self.invites.take_at(&rates.invites, now),
+            _ => Ok(())
+        }
+    }
+
+    // called whenever the user was slowed down, returns the seconds they are muted for once it happened too often
+    pub(crate) fn strike(&mut self, rates: &Rates) -> Option<Seconds> {
+        let now = Instant::now();
+        if rates.strikes == 0 || self.muted_until.is_some_and(|until| until > now) {
+            return None
+        }
+        while self.strikes.front().is_some_and(|strike| now.duration_since(*strike) > STRIKE_WINDOW) {
+            self.strikes.pop_front();
+        }
+        self.strikes.push_back(now);
+        if self.strikes.len() < rates.strikes {
+            return None
+        }
+        self.strikes.clear();
+        self.muted_until = Some(now + Duration::from_secs(rates.mute));
+        Some(rates.mute)
+    }
+
+    // nothing left to remember once the buckets are full again and the user is neither muted nor struck recently
+    fn is_idle(&mut self, rates: &Rates, now: Instant) -> bool {
+        self.messages.is_full(&rates.messages, now)
+            && self.chats.is_full(&rates.chat_creation, now)
+            && self.invites.is_full(&rates.invites, now)
+            && self.muted_until.is_none_or(|until| until <= now)
+            && self.strikes.back().is_none_or(|strike| now.duration_since(*strike) > STRIKE_WINDOW)
+    }
+}
+
+// addresses with a full bucket are forgotten, so the map only holds recent registrations or logins
+pub(crate) async fn take_address(ip: IpAddr, addresses: &Mutex<HashMap<IpAddr, Bucket>>, rate: &Rate) -> WsResult {
+    let now = Instant::now();
+    let mut addresses_w = addresses.lock().await;
+    addresses_w.retain(|_, bucket| !bucket.is_full(rate, now));
+    addresses_w.entry(ip).or_default().take_at(rate, now)
+}
+
+// throttles not used by any connection are forgotten once idle, so the map only holds users online or still limited
+pub(crate) async fn take_throttle(user_id: &UserId, throttles: &Throttles, rates: &Rates) -> Arc<Mutex<Throttle>> {
+    let now = Instant::now();
+    let mut throttles_w = throttles.lock().await;
+    throttles_w.retain(|_, throttle| Arc::strong_count(throttle) > 1 || throttle.try_lock().map_or(true, |mut throttle| !throttle.is_idle(rates, now)));
+    throttles_w.entry(user_id.clone()).or_default().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let rate = Rate { burst: 2, per_minute: 6 };
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        assert_eq!(bucket.take_at(&rate, start), Ok(()));
+        assert_eq!(bucket.take_at(&rate, start), Ok(()));
+        assert_eq!(bucket.take_at(&rate, start), Err(ClcError::SlowDown(10)));
+        assert_eq!(bucket.take_at(&rate, start + Duration::from_secs(10)), Ok(()));
+        assert!(!bucket.is_full(&rate, start + Duration::from_secs(10)));
+        assert!(bucket.is_full(&rate, start + Duration::from_secs(60)));
+        let off = Rate { burst: 0, per_minute: 0 };
+        assert!((0..100).all(|_| bucket.take_at(&off, start).is_ok()));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use clc_lib::{deserialize, serialize};
+use clc_lib::protocol::{ChatId, UserId, UserName};
+use crate::{Account, Chat, Chats, Store, debug, error, info};
+use crate::chat::save_all_chats;
+
+pub(crate) trait Storage: Send + Sync {
+    fn account(&self, user_id: &UserId) -> Option<Account>;
+    fn find_account(&self, user_name: &UserName) -> Option<Account>;
+    fn accounts(&self) -> Vec<Account>;
+    fn chats(&self) -> Vec<Chat>;
+    fn save_account(&mut self, account: &Account);
+    fn save_chat(&mut self, chat: &Chat);
+    fn remove_chat(&mut self, chat_id: &ChatId);
+    // the state to write if it changed since the last snapshot, storages that write nothing have none
+    fn snapshot(&mut self) -> Option<Snapshot> {
+        None
+    }
+}
+
+// a copy of the state, serialized and written outside of the storage lock
+pub(crate) struct Snapshot {
+    path: PathBuf,
+    data: MemoryStorage,
+}
+
+impl Snapshot {
+    fn write(&self) {
+        let content = match serialize(&self.data) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("error serializing storage {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        // write to a temporary file first so a crash mid-write can't corrupt the storage
+        let tmp = self.path.with_extension("tmp");
+        let result = fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            error!("error writing storage {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// keeps everything in memory, nothing survives a restart
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct MemoryStorage {
+    #[serde(default)]
+    accounts: HashMap<UserId, Account>,
+    chats: HashMap<ChatId, Chat>,
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, user_id: &UserId) -> Option<Account> {
+        self.accounts.get(user_id).cloned()
+    }
+
+    fn find_account(&self, user_name: &UserName) -> Option<Account> {
+        self.accounts.values().find(|account| &account.user_name == user_name).cloned()
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.accounts.values().cloned().collect()
+    }
+
+    fn chats(&self) -> Vec<Chat> {
+        self.chats.values().cloned().collect()
+    }
+
+    fn save_account(&mut self, account: &Account) {
+        self.accounts.insert(account.user_id.clone(), account.clone());
+    }
+
+    fn save_chat(&mut self, chat: &Chat) {
+        self.chats.insert(chat.chat_id.clone(), chat.clone());
+    }
+
+    fn remove_chat(&mut self, chat_id: &ChatId) {
+        self.chats.remove(chat_id);
+    }
+}
+
+// same as MemoryStorage, but the whole state is written to a json file by flush_periodically
+#[derive(Debug)]
+pub(crate) struct FileStorage {
+    path: PathBuf,
+    data: MemoryStorage,
+    // changed since the last snapshot
+    dirty: bool,
+}
+
+impl FileStorage {
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let data = if path.exists() {
+            let content = fs::read_to_string(&path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+            deserialize(&content).map_err(|e| format!("unable to parse {}: {}", path.display(), e))?
+        } else {
+            MemoryStorage::default()
+        };
+        debug!("opened storage {}", path.display());
+        Ok(Self { path, data, dirty: false })
+    }
+}
+
+impl Storage for FileStorage {
+    fn account(&self, user_id: &UserId) -> Option<Account> {
+        self.data.account(user_id)
+    }
+
+    fn find_account(&self, user_name: &UserName) -> Option<Account> {
+        self.data.find_account(user_name)
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.data.accounts()
+    }
+
+    fn chats(&self) -> Vec<Chat> {
+        self.data.chats()
+    }
+
+    fn save_account(&mut self, account: &Account) {
+        self.data.save_account(account);
+        self.dirty = true;
+    }
+
+    fn save_chat(&mut self, chat: &Chat) {
+        self.data.save_chat(chat);
+        self.dirty = true;
+    }
+
+    fn remove_chat(&mut self, chat_id: &ChatId) {
+        self.data.remove_chat(chat_id);
+        self.dirty = true;
+    }
+
+    fn snapshot(&mut self) -> Option<Snapshot> {
+        if !self.dirty {
+            return None
+        }
+        self.dirty = false;
+        Some(Snapshot { path: self.path.clone(), data: self.data.clone() })
+    }
+}
+
+// writes the changes every interval seconds and once more when the server is stopped with ctrl-c,
+// a single task does all writes so an older snapshot can't overwrite a newer one
+pub(crate) async fn flush_periodically(store: Store, chats: Chats, interval: u64) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        let stop = tokio::select! {
+            _ = ticks.tick() => false,
+            _ = &mut shutdown => {
+                save_all_chats(&chats).await;
+                true
+            }
+        };
+        let snapshot = store.lock().await.snapshot();
+        if let Some(snapshot) = snapshot {
+            if let Err(e) = tokio::task::spawn_blocking(move || snapshot.write()).await {
+                error!("error writing storage: {}", e);
+            }
+        }
+        if stop {
+            info!("stopped");
+            std::process::exit(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::invites::Invite;
+    use super::*;
+
+    #[test]
+    fn file_storage_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("clc-storage-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage.save_account(&Account {
+                user_id: "u1".to_string(),
+                user_name: "alice".to_string(),
+                password_hash: "hash".to_string(),
+                nick: None,
+            });
+            storage.save_chat(&Chat {
+                chat_id: "c1".to_string(),
+                title: "general".to_string(),
+                owner: "u1".to_string(),
+                admins: Default::default(),
+                users: HashSet::from(["u1".to_string()]),
+                invites: HashMap::from([("i1".to_string(), Invite { creator: "u1".to_string(), uses_left: Some(3), expires: None })]),
+                history: Default::default(),
+                banned: Default::default(),
+                muted: Default::default(),
+                files: Default::default(),
+                visibility: Default::default(),
+                topic: None,
+                fanout: None,
+                message_rate: Default::default(),
+                unsaved: false,
+            });
+            storage.snapshot().unwrap().write();
+            assert!(storage.snapshot().is_none());
+        }
+        let storage = FileStorage::open(&path).unwrap();
+        assert_eq!(storage.find_account(&"alice".to_string()).unwrap().user_id, "u1");
+        assert!(storage.account(&"u2".to_string()).is_none());
+        let chats = storage.chats();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].title, "general");
+        assert_eq!(chats[0].invites["i1"].uses_left, Some(3));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,42 +1,175 @@
-use crate::{Chats, Clients, debug};
+use crate::{Chats, Clients, Config, Sessions, Store, WsResult, debug, error, info};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use futures::{FutureExt, StreamExt};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_stream::wrappers::ReceiverStream;
 use warp::ws::{Message, WebSocket};
 use clc_lib::deserialize;
-use clc_lib::protocol::{ClientWsMessage, ServerWsMessage, UserId};
-use crate::chat::{broadcast_msg, create_chat, create_chat_invite, join_chat, leave_chat, send_msg};
+use clc_lib::protocol::{ClcError, ClientWsMessage, ClientWsRequest, RequestId, ServerWsMessage, SessionToken, UserId};
+use serde::de::IgnoredAny;
+use crate::direct::send_direct_message;
+use crate::names::set_nick;
+use crate::rates::Throttle;
+use crate::chat::{chat_request, create_chat, leave_all_chats, list_public_chats, request_join, send_msg, subscribe_all};
 
-pub(crate) async fn client_connection(ws: WebSocket, user_id: UserId, clients: Clients, chats: Chats) {
-    let (client_ws_sender, mut client_ws_rcv) = ws.split();
-    let (client_sender, client_rcv) = mpsc::unbounded_channel();
+// messages waiting to be sent over a websocket, bounded so a client that stops reading can't make the server buffer without limit
+#[derive(Debug, Clone)]
+pub(crate) struct Outbox {
+    queue: mpsc::Sender<Message>,
+    // notified when the queue is full, the connection is closed then
+    overflow: Arc<Notify>,
+}
+
+impl Outbox {
+    // a client that can't keep up is disconnected instead of queueing more
+    pub(crate) fn push(&self, message: Message) {
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(message) {
+            self.overflow.notify_one();
+        }
+    }
+
+    // waits for room in the queue, false once the connection is closed
+    pub(crate) async fn send(&self, message: Message) -> bool {
+        self.queue.send(message).await.is_ok()
+    }
 
-    let client_rcv = UnboundedReceiverStream::new(client_rcv);
-    tokio::task::spawn(client_rcv.forward(client_ws_sender).map(|result| {
+    pub(crate) async fn closed(&self) {
+        self.queue.closed().await
+    }
+
+    pub(crate) fn same_channel(&self, other: &Outbox) -> bool {
+        self.queue.same_channel(&other.queue)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn client_connection(ws: WebSocket, user_id: UserId, token: SessionToken, clients: Clients, chats: Chats, sessions: Sessions, store: Store, config: Config) {
+    // another connection might have used the token since the upgrade was accepted
+    match sessions.write().await.get_mut(&token) {
+        Some(session) if session.can_connect() => {
+            session.connected = true;
+            session.dropped = None;
+        }
+        _ => return
+    }
+    let (client_ws_sender, mut client_ws_rcv) = ws.split();
+    let (queue, client_rcv) = mpsc::channel(config.connection.queue_size);
+    let overflow = Arc::new(Notify::new());
+    let outbox = Outbox { queue, overflow: overflow.clone() };
+    let throttle = match clients.write().await.get_mut(&user_id) {
+        Some(client) => {
+            client.sender = Some(outbox.clone());
+            client.throttle.clone()
+        }
+        // logged out in the meantime
+        None => {
+            if let Some(session) = sessions.write().await.get_mut(&token) {
+                session.connected = false;
+            }
+            return
+        }
+    };
+    let writer = tokio::task::spawn(ReceiverStream::new(client_rcv).map(Ok).forward(client_ws_sender).map(|result| {
         if let Err(e) = result {
-            eprintln!("error sending websocket msg: {}", e);
+            error!("error sending websocket msg: {}", e);
         }
     }));
-    clients.write().await.get_mut(&user_id).unwrap().sender = Some(client_sender);
 
     debug!("{} connected", user_id);
+    if let Some(motd) = &config.motd {
+        if let Some(client) = clients.read().await.get(&user_id) {
+            send_msg(client, ServerWsMessage::SystemMessage(motd.clone())).await;
+        }
+    }
+    subscribe_all(&user_id, &chats).await;
 
-    while let Some(result) = client_ws_rcv.next().await {
+    // connections that went away without closing are only noticed by pinging them
+    let pings = config.connection.ping_interval > 0;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(config.connection.ping_interval.max(1)));
+    heartbeat.tick().await;
+    let mut unanswered = 0;
+    loop {
+        let result = tokio::select! {
+            result = client_ws_rcv.next() => match result {
+                Some(result) => result,
+                None => break
+            },
+            _ = overflow.notified() => {
+                info!("{} is too slow to receive messages, disconnecting", user_id);
+                break;
+            }
+            _ = heartbeat.tick(), if pings => {
+                if unanswered >= config.connection.missed_pongs {
+                    info!("{} stopped answering pings, disconnecting", user_id);
+                    break;
+                }
+                unanswered += 1;
+                outbox.push(Message::ping(Vec::new()));
+                continue;
+            }
+        };
+        // any frame shows the client is still there, not only pongs
+        unanswered = 0;
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
-                eprintln!("error receiving ws message for id: {}): {}", user_id, e);
+                error!("error receiving ws message for id: {}): {}", user_id, e);
                 break;
             }
         };
-        client_msg(&user_id, msg, &clients, &chats).await;
+        client_msg(&user_id, msg, &throttle, &clients, &chats, &store, &config).await;
     }
-    leave_chat(&user_id, &clients, &chats).await;
-    clients.write().await.remove(&user_id);
-    debug!("{} disconnected + unregistered", user_id);
+    // dropping the queue ends the forwarding of chat messages and closes the socket
+    writer.abort();
+    // the user might have logged out and in again in the meantime, don't touch the new connection
+    let current = match clients.write().await.get_mut(&user_id) {
+        Some(c) if c.sender.as_ref().is_some_and(|s| s.same_channel(&outbox)) => {
+            c.sender = None;
+            true
+        }
+        _ => false
+    };
+    let dropped = match sessions.write().await.get_mut(&token) {
+        Some(session) if current => {
+            let dropped = Instant::now();
+            session.connected = false;
+            session.dropped = Some(dropped);
+            Some(dropped)
+        }
+        _ => None
+    };
+    if let Some(dropped) = dropped {
+        debug!("{} disconnected, keeping the session for {}s", user_id, config.connection.grace_period);
+        tokio::time::sleep(Duration::from_secs(config.connection.grace_period)).await;
+        end_grace_period(&user_id, &token, dropped, &clients, &chats, &sessions).await;
+    } else {
+        sessions.write().await.remove(&token);
+        debug!("{} disconnected", user_id);
+    }
+}
+
+// the user is only removed if they did not reconnect or log in again in the meantime
+async fn end_grace_period(user_id: &UserId, token: &SessionToken, dropped: Instant, clients: &Clients, chats: &Chats, sessions: &Sessions) {
+    {
+        let mut sessions_w = sessions.write().await;
+        match sessions_w.get(token) {
+            Some(session) if session.dropped == Some(dropped) => {
+                sessions_w.remove(token);
+            }
+            _ => return
+        }
+        if sessions_w.values().any(|session| &session.user_id == user_id) {
+            return;
+        }
+    }
+    debug!("grace period of {} ended", user_id);
+    leave_all_chats(user_id, chats).await;
+    clients.write().await.remove(user_id);
 }
 
-async fn client_msg(client_id: &UserId, msg: Message, clients: &Clients, chats: &Chats) {
+async fn client_msg(client_id: &UserId, msg: Message, throttle: &Mutex<Throttle>, clients: &Clients, chats: &Chats, store: &Store, config: &Config) {
     debug!("received ws message from {}: {:?}", client_id, msg);
     let message = match msg.to_str() {
         Ok(v) => v,
@@ -47,54 +180,60 @@ async fn client_msg(client_id: &UserId, msg: Message, clients: &Clients, chats:
         return;
     }
 
-    let cwsm: ClientWsMessage = match deserialize(&message) {
+    let ClientWsRequest(request_id, cwsm) = match deserialize(message) {
         Ok(v) => v,
         Err(e) => {
-            eprintln!("error while parsing message to topics request: {}", e);
+            error!("error while parsing message to topics request: {}", e);
+            // the id is read on its own, so requests that can't be parsed are still answered
+            if let Ok((request_id, IgnoredAny)) = deserialize::<(RequestId, IgnoredAny)>(message) {
+                if let Some(client) = clients.read().await.get(client_id) {
+                    send_msg(client, ServerWsMessage::Reply(request_id, Err(ClcError::InvalidRequest))).await;
+                }
+            }
             return;
         }
     };
 
-    match cwsm {
-        ClientWsMessage::Message(content) => {
-            let (name, chat) = {
-                let clients_r = clients.read().await;
-                let c = clients_r.get(client_id).unwrap();
-                (c.user_name.clone(), c.chat.clone())
-            };
-            if let Some(chat_id) = chat {
-                broadcast_msg(ServerWsMessage::Message(client_id.to_string(), name, content), chats.read().await.get(&chat_id).unwrap(), clients).await;
+    let admitted = throttle.lock().await.admit(&cwsm, &config.rates);
+    let result = match admitted {
+        Ok(()) => handle_request(client_id, cwsm, clients, chats, store, config).await,
+        // only the user's own limits count, a busy chat slowing everyone down is no reason to mute them
+        Err(e) => {
+            if let Some(secs) = throttle.lock().await.strike(&config.rates) {
+                info!("{} sent too much and is muted for {}s", client_id, secs);
+                if let Some(client) = clients.read().await.get(client_id) {
+                    send_msg(client, ServerWsMessage::SystemMessage(format!("You are sending too much and can't send messages for {} minutes", secs.div_ceil(60)))).await;
+                }
             }
+            Err(e)
         }
+    };
+    if let Err(e) = &result {
+        debug!("request {} of {} failed: {:?}", request_id, client_id, e);
+    }
+    if let Some(client) = clients.read().await.get(client_id) {
+        send_msg(client, ServerWsMessage::Reply(request_id, result)).await;
+    }
+}
+
+async fn handle_request(client_id: &UserId, cwsm: ClientWsMessage, clients: &Clients, chats: &Chats, store: &Store, config: &Config) -> WsResult {
+    match cwsm {
         ClientWsMessage::ChatCreate(title) => {
-            create_chat(title, client_id, clients, chats).await;
-        }
-        ClientWsMessage::ChatCreateInvite => {
-            create_chat_invite(client_id, clients, chats).await;
+            create_chat(title, client_id, clients, chats, store, config).await
         }
         ClientWsMessage::ChatJoin(chat_title, invite_id) => {
-            join_chat(client_id, chat_title, invite_id, clients, chats).await;
-        }
-        ClientWsMessage::ChatLeave => {
-            leave_chat(client_id, clients, chats).await;
-        }
-        ClientWsMessage::ChatListMembers => {
-            let clients_r = clients.read().await;
-            let c = clients_r.get(client_id).unwrap();
-            if let Some(chat_id) = c.chat.as_ref() {
-                let chats_r = chats.read().await;
-                let chat = chats_r.get(chat_id).unwrap();
-                let mut response = String::new();
-                response.push_str(&format!("members of {}:\n", chat.title));
-                for user in chat.users.iter() {
-                    response.push_str(&format!("    {}\n", clients_r.get(user).unwrap().user_name));
-                }
-                response.push_str(&format!("open invites:\n"));
-                for invite in chat.invites.iter() {
-                    response.push_str(&format!("    {}\n", invite));
-                }
-                send_msg(c, ServerWsMessage::SystemMessage(response)).await;
-            }
+            request_join(client_id, chat_title, invite_id, chats).await
+        }
+        ClientWsMessage::ChatDirectory => {
+            list_public_chats(client_id, clients, chats).await
         }
+        ClientWsMessage::DirectMessage(target, content) => {
+            send_direct_message(client_id, target, content, clients, config).await
+        }
+        ClientWsMessage::SetNick(nick) => {
+            set_nick(client_id, nick, clients, chats, store, config).await
+        }
+        // everything else concerns a single chat and is handled by it
+        message => chat_request(client_id, message, chats).await
     }
-}
\ No newline at end of file
+}